@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use x25519_dalek::PublicKey;
+
+use crate::util::parse_public_key;
+
+// Which domains and paths a client may claim. The file is a JSON list of
+// rules, a client may use a domain/path when any of its rules matches both.
+//
+// [
+//   {"client": "<base64 public key>", "domains": ["*.team-a.example.com"], "paths": ["/api/*"]},
+//   {"client": "*", "domains": ["public.example.com"]}
+// ]
+#[derive(Deserialize)]
+struct RuleConfig {
+    client: String,
+    domains: Vec<String>,
+    #[serde(default = "any")]
+    paths: Vec<String>,
+}
+
+fn any() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+struct Rule {
+    // None matches every client
+    client: Option<PublicKey>,
+    domains: Vec<String>,
+    paths: Vec<String>,
+}
+
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: &str) -> anyhow::Result<Acl> {
+        Acl::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Acl> {
+        let rules = serde_json::from_str::<Vec<RuleConfig>>(s)?
+            .into_iter()
+            .map(|r| {
+                let client = if r.client == "*" {
+                    None
+                } else {
+                    Some(parse_public_key(&r.client)?)
+                };
+                Ok(Rule {
+                    client,
+                    domains: r.domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+                    paths: r.paths,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Acl { rules })
+    }
+
+    pub fn allows(&self, client: &PublicKey, domain: &str, path: &str) -> bool {
+        let domain = domain.to_ascii_lowercase();
+        self.rules.iter().any(|r| {
+            r.client.is_none_or(|c| &c == client)
+                && r.domains.iter().any(|p| matches(p, &domain))
+                && r.paths.iter().any(|p| matches(p, path))
+        })
+    }
+}
+
+// Glob match where '*' stands for any (possibly empty) sequence.
+fn matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose, Engine as _};
+
+    #[test]
+    fn glob() {
+        assert!(matches("*", ""));
+        assert!(matches("a.example.com", "a.example.com"));
+        assert!(!matches("a.example.com", "b.example.com"));
+        assert!(matches("*.example.com", "a.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(matches("/api/*", "/api/v1"));
+        assert!(!matches("/api/*", "/apiv1"));
+        assert!(matches("a*b*c", "aXbYc"));
+        assert!(!matches("a*b*c", "aXcYb"));
+        assert!(!matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn rules() -> anyhow::Result<()> {
+        let a = PublicKey::from([1u8; 32]);
+        let b = PublicKey::from([2u8; 32]);
+        let acl = Acl::parse(&format!(
+            r#"[
+                {{"client": "{}", "domains": ["*.team-a.example.com"], "paths": ["/api/*"]}},
+                {{"client": "*", "domains": ["public.example.com"]}}
+            ]"#,
+            general_purpose::STANDARD.encode(a.as_bytes())
+        ))?;
+
+        assert!(acl.allows(&a, "x.team-a.example.com", "/api/v1"));
+        assert!(acl.allows(&a, "X.Team-A.example.com", "/api/v1"));
+        assert!(!acl.allows(&a, "x.team-a.example.com", "/"));
+        assert!(!acl.allows(&b, "x.team-a.example.com", "/api/v1"));
+        assert!(acl.allows(&a, "public.example.com", "/"));
+        assert!(acl.allows(&b, "public.example.com", "/anything"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use rand::Rng;
+use std::time::Duration;
+
+// Capped exponential backoff with equal jitter: the n-th delay is drawn from
+// [cap / 2, cap] where cap = min(max, base * 2^n).
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let cap = self
+            .base
+            .checked_mul(1 << std::cmp::min(self.attempt, 31))
+            .map_or(self.max, |d| std::cmp::min(d, self.max));
+        self.attempt = self.attempt.saturating_add(1);
+        let half = cap / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=cap - half)
+    }
+
+    // Number of delays handed out since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let base = Duration::from_millis(500);
+        let max = Duration::from_secs(30);
+        let mut backoff = Backoff::new(base, max);
+        for n in 0..40 {
+            let cap = std::cmp::min(max, base * 2u32.pow(std::cmp::min(n, 10)));
+            let delay = backoff.next_delay();
+            assert!(delay >= cap / 2 && delay <= cap, "{} {:?}", n, delay);
+        }
+        assert_eq!(backoff.attempt(), 40);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+}
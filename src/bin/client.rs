@@ -1,15 +1,20 @@
 use clap::Parser;
 use futures::{SinkExt, TryStreamExt};
-use revconn::encstream::{DecStream, EncStream};
-use revconn::protocol::Message;
-use revconn::util::{get_key_and_nonce_from_env, handle_connection};
-use std::collections::HashMap;
+use revconn::backoff::Backoff;
+use revconn::encstream::SealedCodec;
+use revconn::handshake::client_handshake;
+use revconn::heartbeat::Heartbeat;
+use revconn::protocol::{Capabilities, CloseReason, Message, PROTOCOL_VERSION};
+use revconn::util::{
+    connect_backend, get_identity_from_env, parse_public_key, ConnectionTable, Route,
+};
 use std::sync::Mutex;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::Sender;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,6 +22,10 @@ struct Args {
     #[arg(long)]
     server: String,
 
+    /// base64 public key of the server
+    #[arg(long)]
+    server_key: String,
+
     #[arg(long)]
     backend: String,
 
@@ -25,11 +34,73 @@ struct Args {
 
     #[arg(long)]
     path: Option<String>,
+
+    /// stable id of this client, lets a reconnect take over the old session,
+    /// a random id kept for the lifetime of the process when unset
+    #[arg(long)]
+    tunnel_id: Option<String>,
+
+    /// fixed port to expose the tunnel on, must be allowed by the server
+    #[arg(long)]
+    remote_port: Option<u16>,
+
+    /// only take traffic once the active client for the domain/path drops
+    #[arg(long)]
+    standby: bool,
+
+    /// print where the tunnel is exposed as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// seconds to wait for the backend to accept a connection
+    #[arg(long, default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// upper bound in seconds for the delay between reconnect attempts
+    #[arg(long, default_value_t = 30)]
+    max_reconnect_delay: u64,
+
+    /// seconds between heartbeat pings, 0 disables heartbeats
+    #[arg(long, default_value_t = 15)]
+    heartbeat_interval: u64,
+
+    /// seconds without a pong before the session is considered dead
+    #[arg(long, default_value_t = 45)]
+    heartbeat_timeout: u64,
 }
 
+// The server turned the session down, retrying would not change that.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "rejected by server: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// The server speaks another protocol version, retrying would not change that
+// either.
+#[derive(Debug)]
+struct Incompatible(u32);
+
+impl std::fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "server speaks protocol version {}, client speaks {}",
+            self.0, PROTOCOL_VERSION
+        )
+    }
+}
+
+impl std::error::Error for Incompatible {}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::Layer::new()
@@ -40,60 +111,151 @@ async fn main() -> anyhow::Result<()> {
         )
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .try_init()?;
-    let mut key = [0x42; 32];
-    let mut nonce = [0x24; 12];
+    // the same id on every reconnect, so a session the server still thinks
+    // alive is taken over instead of turning the client away
+    if args.tunnel_id.is_none() {
+        args.tunnel_id = Some(uuid::Uuid::new_v4().to_string());
+    }
+    let identity = get_identity_from_env()?;
+    let server_key = parse_public_key(&args.server_key)?;
 
-    get_key_and_nonce_from_env(&mut key, &mut nonce);
+    let mut backoff = Backoff::new(
+        Duration::from_millis(500),
+        Duration::from_secs(args.max_reconnect_delay),
+    );
+    let mut attempts = 0u64;
+    loop {
+        attempts += 1;
+        info!(
+            "connecting to {}, attempt={}, consecutive failures={}",
+            args.server,
+            attempts,
+            backoff.attempt()
+        );
+        match run(&args, &identity, &server_key, &mut backoff).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is::<Rejected>() || e.is::<Incompatible>() => return Err(e),
+            Err(e) => warn!("session ended, attempt={}, {}", attempts, e),
+        }
+        let delay = backoff.next_delay();
+        info!("reconnecting in {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
 
-    let mut conn = TcpStream::connect(args.server).await?;
+// One session with the server. Returns Ok when the server shut the session
+// down on purpose, the backoff is reset once the hello exchange succeeded.
+async fn run(
+    args: &Args,
+    identity: &StaticSecret,
+    server_key: &PublicKey,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let mut conn = TcpStream::connect(&args.server).await?;
+    let keys = client_handshake(&mut conn, identity, server_key).await?;
 
     let (ri, wi) = conn.split();
-    let ri = DecStream::new(ri, &key, &nonce);
-    let wi = EncStream::new(wi, &key, &nonce);
 
     let mut ri = {
-        // Delimit frames using a length header
-        let length_delimited =
-            tokio_util::codec::FramedRead::new(ri, tokio_util::codec::LengthDelimitedCodec::new());
+        // Delimit and authenticate frames
+        let sealed = tokio_util::codec::FramedRead::new(
+            ri,
+            SealedCodec::new(&keys.s2c_key, &keys.s2c_nonce),
+        );
 
         // Deserialize frames
-        let deserialized = tokio_serde::SymmetricallyFramed::new(
-            length_delimited,
+        tokio_serde::SymmetricallyFramed::new(
+            sealed,
             tokio_serde::formats::SymmetricalBincode::<Message>::default(),
-        );
-        deserialized
+        )
     };
 
     let mut wi = {
-        // Delimit frames using a length header
-        let length_delimited =
-            tokio_util::codec::FramedWrite::new(wi, tokio_util::codec::LengthDelimitedCodec::new());
+        // Delimit and seal frames
+        let sealed = tokio_util::codec::FramedWrite::new(
+            wi,
+            SealedCodec::new(&keys.c2s_key, &keys.c2s_nonce),
+        );
 
         // Serialize frames with JSON
-        let serialized = tokio_serde::SymmetricallyFramed::new(
-            length_delimited,
+        tokio_serde::SymmetricallyFramed::new(
+            sealed,
             tokio_serde::formats::SymmetricalBincode::<Message>::default(),
-        );
-        serialized
+        )
     };
 
+    let mut capabilities = Capabilities::supported();
+    if args.heartbeat_interval == 0 {
+        capabilities = capabilities.without(Capabilities::HEARTBEAT);
+    }
     wi.send(Message::ClientHello {
-        domain: args.domain,
-        path: args.path,
+        version: PROTOCOL_VERSION,
+        capabilities,
+        domain: args.domain.clone(),
+        path: args.path.clone(),
+        tunnel_id: args.tunnel_id.clone(),
+        port: args.remote_port,
+        standby: args.standby,
     })
     .await?;
     debug!("send hello to server");
 
-    match ri.try_next().await?.ok_or(anyhow::anyhow!("not message"))? {
-        Message::ServerHello { domain, path } => {
-            println!("{}, {}", domain, path);
+    let capabilities = match ri.try_next().await?.ok_or(anyhow::anyhow!("not message"))? {
+        Message::ServerHello {
+            version,
+            capabilities,
+            domain,
+            path,
+            port,
+            bind,
+            url,
+            role,
+        } => {
+            if version != PROTOCOL_VERSION {
+                Err(Incompatible(version))?;
+            }
+            if args.json {
+                let endpoint = serde_json::json!({
+                    "domain": domain,
+                    "path": path,
+                    "port": port,
+                    "bind": bind,
+                    "url": url,
+                    "role": role.to_string(),
+                });
+                println!("{}", endpoint);
+            } else {
+                // brackets around IPv6 addresses
+                let addr = match bind.parse::<std::net::IpAddr>() {
+                    Ok(ip) => std::net::SocketAddr::new(ip, port).to_string(),
+                    Err(_) => format!("{}:{}", bind, port),
+                };
+                match url {
+                    Some(url) => println!("{}, {}, {}, {}, {}", domain, path, addr, role, url),
+                    None => println!("{}, {}, {}, {}", domain, path, addr, role),
+                }
+            }
+            capabilities
         }
+        Message::Reject { reason } => Err(Rejected(reason))?,
+        // held by another session for now, retried with the backoff
+        Message::Busy { reason } => Err(anyhow::anyhow!("server busy: {}", reason))?,
         _ => Err(anyhow::anyhow!("fail handshaking"))?,
-    }
-    debug!("get hello from server");
+    };
+    debug!("get hello from server, capabilities={:?}", capabilities);
+    backoff.reset();
+
+    let mut heartbeat = if capabilities.contains(Capabilities::HEARTBEAT) {
+        Heartbeat::new(
+            Duration::from_secs(args.heartbeat_interval),
+            Duration::from_secs(args.heartbeat_timeout),
+        )
+    } else {
+        Heartbeat::disabled()
+    };
 
     let (gtx, mut grx) = tokio::sync::mpsc::channel::<Message>(32);
-    let conn_map: Mutex<HashMap<u32, Sender<Message>>> = Mutex::new(HashMap::new());
+    let conn_map: Mutex<ConnectionTable> = Mutex::new(ConnectionTable::default());
 
     loop {
         tokio::select! {
@@ -106,31 +268,73 @@ async fn main() -> anyhow::Result<()> {
 
                 match message {
                     Message::NewConnection { id } => {
-                        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(32);
-                        {
-                            let mut m = conn_map.lock().unwrap();
-                            m.insert(id, tx);
-                        }
+                        let rx = conn_map.lock().unwrap().open(id, capabilities);
 
-                        let conn = TcpStream::connect(args.backend.clone()).await?;
+                        // connect in the task so a slow backend does not hold up
+                        // other connections, early data waits in the channel
                         let gtx = gtx.clone();
-                        tokio::spawn(handle_connection(id, rx, gtx, conn));
+                        tokio::spawn(connect_backend(
+                            id,
+                            TcpStream::connect(args.backend.clone()),
+                            Duration::from_secs(args.connect_timeout),
+                            rx,
+                            gtx,
+                            capabilities,
+                        ));
                     }
-                    Message::Data { id, data } => {
-                        let tx = match conn_map.lock().unwrap().get(&id) {
-                            Some(tx) => tx.clone(),
-                            None => Err(anyhow::anyhow!("key not found in conn map"))?,
-                        };
-                        tx.send(Message::Data {id, data}).await?;
+                    Message::Data { id, .. }
+                    | Message::WindowUpdate { id, .. }
+                    | Message::ShutdownWrite { id } => {
+                        let route = conn_map.lock().unwrap().route(id, message);
+                        match route {
+                            Route::Queued => {}
+                            // racing with our own close
+                            Route::Closed(message) => {
+                                debug!(
+                                    "drop {:?} for closed connection, stray frames={}",
+                                    message,
+                                    conn_map.lock().unwrap().stray_frames()
+                                );
+                            }
+                            Route::Unknown(message) => {
+                                warn!("{:?} for connection never opened", message);
+                                wi.send(Message::CloseConnection {
+                                    id,
+                                    reason: CloseReason::PolicyReject,
+                                })
+                                .await?;
+                            }
+                            Route::Overrun => {
+                                warn!("connection id={} sent more than its window, reset", id);
+                                wi.send(Message::CloseConnection {
+                                    id,
+                                    reason: CloseReason::PolicyReject,
+                                })
+                                .await?;
+                            }
+                        }
                     }
-                    Message::CloseConnection { id } => {
-                        let mut m = conn_map.lock().unwrap();
-                        m.remove(&id);
+                    Message::CloseConnection { id, reason } => {
+                        // closed by the server, the task's own close is not echoed back
+                        let tx = conn_map.lock().unwrap().remove(id);
+                        if let Some(tx) = tx {
+                            // a full queue drops the channel instead, which resets
+                            let _ = tx.try_send(Message::CloseConnection { id, reason });
+                        }
                     }
                     Message::Shutdown { message } => {
                         info!("shutdown {:?}", message);
                         break;
                     }
+                    Message::Ping { seq } => {
+                        wi.send(Message::Pong { seq }).await?;
+                    }
+                    Message::Promote => {
+                        info!("promoted from standby to active");
+                    }
+                    Message::Pong { seq } => {
+                        heartbeat.pong(seq);
+                    }
                     _ => {
                         Err(anyhow::anyhow!("unknown message type from server"))?;
                     }
@@ -140,18 +344,27 @@ async fn main() -> anyhow::Result<()> {
                 let message = message.ok_or(anyhow::anyhow!("no message found"))?;
                 debug!("get message from proxy {:?}", message);
                 match message {
-                    Message::Data{id, data} => {
-                        wi.send(Message::Data { id, data }).await?;
+                    Message::Data{..} | Message::ShutdownWrite{..} => {
+                        wi.send(message).await?;
                     }
-                    Message::CloseConnection{id} => {
-                        let mut m = conn_map.lock().unwrap();
-                        m.remove(&id);
+                    Message::WindowUpdate{id, bytes} => {
+                        conn_map.lock().unwrap().grant(id, bytes);
+                        wi.send(message).await?;
+                    }
+                    Message::CloseConnection{id, reason} => {
+                        let removed = conn_map.lock().unwrap().remove(id).is_some();
+                        if removed {
+                            wi.send(Message::CloseConnection { id, reason }).await?;
+                        }
                     }
                     _ => {
                         Err(anyhow::anyhow!("unknown message type from proxy"))?;
                     }
                 }
             }
+            ping = heartbeat.tick() => {
+                wi.send(ping?).await?;
+            }
         }
     }
 
@@ -1,18 +1,36 @@
+use base64::{engine::general_purpose, Engine as _};
 use clap::Parser;
-use futures::{FutureExt, SinkExt, TryStreamExt};
+use futures::{FutureExt, Sink, SinkExt, TryStreamExt};
 use revconn::{
-    encstream::{DecStream, EncStream},
-    protocol::{ExternalMessage, Message},
-    util::{get_key_and_nonce_from_env, handle_connection},
+    acl::Acl,
+    callback,
+    encstream::SealedCodec,
+    handshake::server_handshake,
+    heartbeat::Heartbeat,
+    http,
+    ports::{bind, Exhausted, PortRange, Ports},
+    protocol::{Capabilities, CloseReason, Message, PROTOCOL_VERSION},
+    sni,
+    tls::{CertSpec, Certs},
+    tunnel::{Balance, Claim, Conflict, DispatchPolicy, Event, Listener, Registry, Tunnel},
+    util::{
+        get_identity_from_env, handle_connection, load_authorized_keys, ConnectionTable, Route,
+    },
+};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::{collections::HashMap, sync::Mutex};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc::Sender,
+    time::{timeout_at, Instant},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use x25519_dalek::StaticSecret;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -22,6 +40,81 @@ struct Args {
 
     #[arg(long)]
     callback: Option<String>,
+
+    /// file with the base64 public keys of the clients allowed to connect
+    #[arg(long)]
+    authorized_keys: String,
+
+    /// JSON file restricting the domains and paths each client may claim
+    #[arg(long)]
+    acl: Option<String>,
+
+    /// seconds between heartbeat pings, 0 disables heartbeats
+    #[arg(long, default_value_t = 15)]
+    heartbeat_interval: u64,
+
+    /// seconds without a pong before a session is considered dead
+    #[arg(long, default_value_t = 45)]
+    heartbeat_timeout: u64,
+
+    /// seconds a new session has to complete the handshake and send its hello
+    #[arg(long, default_value_t = 10)]
+    handshake_timeout: u64,
+
+    /// seconds a tunnel's port is kept for its client to reconnect
+    #[arg(long, default_value_t = 60)]
+    grace_period: u64,
+
+    /// what to do when a domain/path is claimed while another client serves it
+    #[arg(long, value_enum, default_value_t = Conflict::Reject)]
+    conflict: Conflict,
+
+    /// how connections are spread over the clients pooled on a tunnel
+    #[arg(long, value_enum, default_value_t = Balance::RoundRobin)]
+    balance: Balance,
+
+    /// external connections held per tunnel while its client is away
+    #[arg(long, default_value_t = 64)]
+    queue_size: usize,
+
+    /// seconds a held connection waits for the client to come back
+    #[arg(long, default_value_t = 10)]
+    queue_timeout: u64,
+
+    /// public URL reported to clients, {domain}, {path} and {port} are
+    /// filled in, e.g. https://{domain}{path}
+    #[arg(long)]
+    public_url: Option<String>,
+
+    /// ports exposed listeners are bound to and clients may ask for,
+    /// e.g. 2000-2999, any free port when unset
+    #[arg(long)]
+    port_range: Option<PortRange>,
+
+    /// address exposed listeners are bound to, :: listens on IPv6 and IPv4
+    #[arg(long, default_value = "0.0.0.0")]
+    expose_addr: IpAddr,
+
+    /// shared listener routing HTTP requests to tunnels by Host header and
+    /// path, e.g. 0.0.0.0:80
+    #[arg(long)]
+    http_bind: Option<String>,
+
+    /// shared listener routing TLS connections to tunnels by the server name
+    /// of their ClientHello, TLS is only terminated for domains with a
+    /// --tls-cert, e.g. 0.0.0.0:443
+    #[arg(long)]
+    sni_bind: Option<String>,
+
+    /// certificate and key to terminate TLS with on the exposed listeners of
+    /// a domain, DOMAIN=CERT,KEY, the domain may be a wildcard like
+    /// *.example.com, repeat for more domains
+    #[arg(long)]
+    tls_cert: Vec<CertSpec>,
+
+    /// seconds between checks for changed certificate files
+    #[arg(long, default_value_t = 10)]
+    tls_reload_interval: u64,
 }
 
 #[tokio::main]
@@ -38,20 +131,49 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .try_init()?;
 
-    let mut key = [0x42; 32];
-    let mut nonce = [0x24; 12];
-    get_key_and_nonce_from_env(&mut key, &mut nonce);
+    let identity = get_identity_from_env()?;
+    // fail early on a broken file, it is read again for every session
+    load_authorized_keys(&args.authorized_keys)?;
+    if let Some(acl) = &args.acl {
+        Acl::load(acl)?;
+    }
 
-    let listen_addr = args.bind.unwrap_or("0.0.0.0:8000".to_string());
+    let listen_addr = args.bind.clone().unwrap_or("0.0.0.0:8000".to_string());
     let listener = TcpListener::bind(listen_addr).await?;
+    let registry = Registry::new(Duration::from_secs(args.grace_period), args.conflict);
+    let ports = args.port_range.map(Ports::new);
+    let certs = if args.tls_cert.is_empty() {
+        None
+    } else {
+        let certs = Certs::load(args.tls_cert.clone())?;
+        let interval = Duration::from_secs(args.tls_reload_interval);
+        tokio::spawn(certs.clone().watch(interval));
+        Some(certs)
+    };
+    if let Some(http_bind) = &args.http_bind {
+        let listener = TcpListener::bind(http_bind).await?;
+        tokio::spawn(route(listener, registry.clone(), http::serve::<TcpStream>));
+    }
+    if let Some(sni_bind) = &args.sni_bind {
+        let listener = TcpListener::bind(sni_bind).await?;
+        tokio::spawn(route(listener, registry.clone(), sni::serve));
+    }
+    let args = Arc::new(args);
 
     while let Ok((inbound, _)) = listener.accept().await {
-        let transfer =
-            transfer(inbound, key.clone(), nonce.clone(), args.callback.clone()).map(|r| {
-                if let Err(e) = r {
-                    println!("Failed to transfer; error={}", e);
-                }
-            });
+        let transfer = transfer(
+            inbound,
+            identity.clone(),
+            args.clone(),
+            registry.clone(),
+            ports.clone(),
+            certs.clone(),
+        )
+        .map(|r| {
+            if let Err(e) = r {
+                warn!("failed to transfer; error={}", e);
+            }
+        });
 
         tokio::spawn(transfer);
     }
@@ -59,184 +181,454 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-struct OnShutdown {
-    callback: String,
-    conn_id: String,
+// Accept loop of a shared listener, serve picks the tunnel for each connection.
+async fn route<F, R>(listener: TcpListener, registry: Arc<Registry>, serve: F)
+where
+    F: Fn(TcpStream, SocketAddr, Arc<Registry>) -> R,
+    R: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    while let Ok((conn, addr)) = listener.accept().await {
+        tokio::spawn(serve(conn, addr, registry.clone()).map(|r| {
+            if let Err(e) = r {
+                debug!("routing failed; error={}", e);
+            }
+        }));
+    }
+}
+
+// Bind the exposed port for a new tunnel.
+async fn open_tunnel(
+    args: &Args,
+    ports: Option<&Ports>,
+    certs: Option<&Arc<Certs>>,
     domain: String,
     path: String,
-    port: u16,
+    port: Option<u16>,
+) -> anyhow::Result<Tunnel> {
+    let (listener, port_lease) = match (ports, port) {
+        (Some(ports), Some(port)) => {
+            // a tunnel dropped just now releases the port once its listener
+            // is closed
+            let lease = ports
+                .reserve_within(port, Duration::from_secs(1))
+                .await
+                .ok_or(std::io::Error::from(std::io::ErrorKind::AddrInUse))?;
+            (bind(SocketAddr::new(args.expose_addr, port))?, Some(lease))
+        }
+        (Some(ports), None) => {
+            let (listener, lease) = ports.bind(args.expose_addr)?;
+            (listener, Some(lease))
+        }
+        // requested ports were turned down without a range
+        (None, _) => (bind(SocketAddr::new(args.expose_addr, 0))?, None),
+    };
+    let conn_uid = format!("conn-{}", hex::encode(uuid::Uuid::new_v4().as_bytes()));
+    let port = listener.local_addr()?.port();
+    let policy = DispatchPolicy {
+        balance: args.balance,
+        queue_size: args.queue_size,
+        queue_timeout: Duration::from_secs(args.queue_timeout),
+    };
+    let tls = certs.and_then(|certs| certs.acceptor(&domain));
+    if tls.is_some() {
+        info!("terminating TLS for {} on port {}", domain, port);
+    }
+    Ok(Tunnel::new(
+        conn_uid,
+        domain,
+        path,
+        Listener { tcp: listener, tls },
+        port_lease,
+        policy,
+    )?)
 }
 
-impl Drop for OnShutdown {
-    fn drop(&mut self) {
-        let callback = self.callback.clone();
-        let conn_id = self.conn_id.clone();
-        let domain = self.domain.clone();
-        let path = self.path.clone();
-        let port = self.port;
-        tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            match client
-                .post(callback)
-                .header("Content-Type", "application/json")
-                .body(
-                    serde_json::to_string(&ExternalMessage::ShutdownConnection {
-                        conn_id,
-                        domain,
-                        path,
-                        port,
-                    })
-                    .unwrap(),
-                )
-                .send()
-                .await
-            {
-                Ok(_) => {}
-                Err(err) => error!("Error sending message to callback {:?}", err),
-            };
-        });
+// Send Reject instead of ServerHello and close the session.
+async fn reject<W>(wi: &mut W, reason: String) -> anyhow::Result<()>
+where
+    W: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    info!("reject client: {}", reason);
+    wi.send(Message::Reject { reason }).await?;
+    wi.close().await?;
+    Ok(())
+}
+
+// Turn the session down for now. Clients that understand Busy try again
+// later, older ones only know Reject.
+async fn busy<W>(wi: &mut W, capabilities: Capabilities, reason: String) -> anyhow::Result<()>
+where
+    W: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    if !capabilities.contains(Capabilities::BUSY) {
+        return reject(wi, reason).await;
     }
+    info!("client busy: {}", reason);
+    wi.send(Message::Busy { reason }).await?;
+    wi.close().await?;
+    Ok(())
 }
 
 async fn transfer(
     mut inbound: TcpStream,
-    key: [u8; 32],
-    nonce: [u8; 12],
-    callback: Option<String>,
+    identity: StaticSecret,
+    args: Arc<Args>,
+    registry: Arc<Registry>,
+    ports: Option<Ports>,
+    certs: Option<Arc<Certs>>,
 ) -> anyhow::Result<()> {
     let mut conn_id = 0;
-    // let (c2s_tx, mut c2s_rx) = tokio::sync::mpsc::channel::<Message>(32);
     let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel::<Message>(32);
-    let conn_map: Mutex<HashMap<u32, Sender<Message>>> = Mutex::new(HashMap::new());
+    let conn_map: Mutex<ConnectionTable> = Mutex::new(ConnectionTable::default());
+
+    // read again for every session so that a removed key takes effect at once
+    let authorized = {
+        let path = args.authorized_keys.clone();
+        tokio::task::spawn_blocking(move || load_authorized_keys(&path)).await??
+    };
+    // a peer that connects and stays silent must not hold the session forever
+    let deadline = Instant::now() + Duration::from_secs(args.handshake_timeout);
+    let (keys, client_key) = timeout_at(
+        deadline,
+        server_handshake(&mut inbound, &identity, &authorized),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("timeout during handshake"))??;
+    debug!(
+        "client authenticated key={}",
+        general_purpose::STANDARD.encode(client_key.as_bytes())
+    );
 
     let (ri, wi) = inbound.split();
-    let ri = DecStream::new(ri, &key, &nonce);
-    let wi = EncStream::new(wi, &key, &nonce);
 
     let mut ri = {
-        // Delimit frames using a length header
-        let length_delimited =
-            tokio_util::codec::FramedRead::new(ri, tokio_util::codec::LengthDelimitedCodec::new());
+        // Delimit and authenticate frames
+        let sealed = tokio_util::codec::FramedRead::new(
+            ri,
+            SealedCodec::new(&keys.c2s_key, &keys.c2s_nonce),
+        );
 
         // Deserialize frames
-        let deserialized = tokio_serde::SymmetricallyFramed::new(
-            length_delimited,
+        tokio_serde::SymmetricallyFramed::new(
+            sealed,
             tokio_serde::formats::SymmetricalBincode::<Message>::default(),
-        );
-        deserialized
+        )
     };
 
     let mut wi = {
-        // Delimit frames using a length header
-        let length_delimited =
-            tokio_util::codec::FramedWrite::new(wi, tokio_util::codec::LengthDelimitedCodec::new());
+        // Delimit and seal frames
+        let sealed = tokio_util::codec::FramedWrite::new(
+            wi,
+            SealedCodec::new(&keys.s2c_key, &keys.s2c_nonce),
+        );
 
         // Serialize frames with JSON
-        let serialized = tokio_serde::SymmetricallyFramed::new(
-            length_delimited,
+        tokio_serde::SymmetricallyFramed::new(
+            sealed,
             tokio_serde::formats::SymmetricalBincode::<Message>::default(),
-        );
-        serialized
+        )
     };
 
-    let client = reqwest::Client::new();
-    let (domain, path) = match ri.try_next().await?.ok_or(anyhow::anyhow!("not message"))? {
-        Message::ClientHello { domain, path } => {
+    let hello = timeout_at(deadline, ri.try_next())
+        .await
+        .map_err(|_| anyhow::anyhow!("timeout waiting for hello"))?;
+    let hello = match hello {
+        Ok(hello) => hello.ok_or(anyhow::anyhow!("not message"))?,
+        // most likely a client built against another protocol version
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            debug!("failed to decode hello {:?}", e);
+            return reject(
+                &mut wi,
+                format!(
+                    "incompatible hello, server speaks protocol version {}",
+                    PROTOCOL_VERSION
+                ),
+            )
+            .await;
+        }
+        Err(e) => Err(e)?,
+    };
+    let (domain, path, tunnel_id, port, standby, capabilities) = match hello {
+        Message::ClientHello {
+            version,
+            capabilities,
+            domain,
+            path,
+            tunnel_id,
+            port,
+            standby,
+        } => {
+            if version != PROTOCOL_VERSION {
+                let age = if version < PROTOCOL_VERSION {
+                    "too old"
+                } else {
+                    "too new"
+                };
+                return reject(
+                    &mut wi,
+                    format!(
+                        "client protocol version {} is {}, server speaks protocol version {}",
+                        version, age, PROTOCOL_VERSION
+                    ),
+                )
+                .await;
+            }
+
             let path = path.unwrap_or("/".to_string());
-            let path = if path == "" { "/".to_string() } else { path };
+            let path = if path.is_empty() {
+                "/".to_string()
+            } else {
+                path
+            };
+            // checked before the ACL, "ample/" on a.ex must not pass for
+            // another client's a.example
+            if !path.starts_with('/') {
+                return reject(&mut wi, format!("path {:?} does not start with /", path)).await;
+            }
 
-            (domain, path)
+            let mut supported = Capabilities::supported();
+            if args.heartbeat_interval == 0 {
+                supported = supported.without(Capabilities::HEARTBEAT);
+            }
+            (
+                domain,
+                path,
+                tunnel_id,
+                port,
+                standby,
+                capabilities.intersection(supported),
+            )
         }
         _ => Err(anyhow::anyhow!("invalid Message"))?,
     };
 
-    let listener = TcpListener::bind("0.0.0.0:0").await?;
-    let conn_uid = format!("conn-{}", hex::encode(uuid::Uuid::new_v4().as_bytes()));
-    debug!(
-        "handshake complete waiting uid={}, port={}",
-        conn_uid,
-        listener.local_addr()?.port()
-    );
+    if let Some(acl) = &args.acl {
+        let acl = {
+            let path = acl.clone();
+            tokio::task::spawn_blocking(move || Acl::load(&path)).await??
+        };
+        if !acl.allows(&client_key, &domain, &path) {
+            return reject(
+                &mut wi,
+                format!(
+                    "client key={} is not allowed to claim domain={}, path={}",
+                    general_purpose::STANDARD.encode(client_key.as_bytes()),
+                    domain,
+                    path
+                ),
+            )
+            .await;
+        }
+    }
 
-    let _on_shutdown = if let Some(callback) = callback {
-        match client
-            .post(&callback)
-            .header("Content-Type", "application/json")
-            .body(
-                serde_json::to_string(&ExternalMessage::NewConnection {
-                    conn_id: conn_uid.clone(),
-                    domain: domain.clone(),
-                    path: path.clone(),
-                    port: listener.local_addr()?.port(),
-                })
-                .unwrap(),
+    if let Some(port) = port {
+        if !ports.as_ref().is_some_and(|p| p.range().contains(port)) {
+            let allowed = match &args.port_range {
+                Some(range) => format!("ports {}", range),
+                None => "no fixed ports".to_string(),
+            };
+            return reject(
+                &mut wi,
+                format!("port {} is not allowed, server allows {}", port, allowed),
             )
-            .send()
-            .await
+            .await;
+        }
+    }
+
+    let (mut lease, opened) = loop {
+        match registry.claim(
+            &domain,
+            &path,
+            port,
+            &client_key,
+            tunnel_id.as_deref(),
+            standby,
+        ) {
+            Claim::Attached(lease) => {
+                info!(
+                    "attach to tunnel domain={}, path={}, role={}",
+                    domain, path, lease.role
+                );
+                break (lease, false);
+            }
+            Claim::Busy(reason) => return busy(&mut wi, capabilities, reason).await,
+            Claim::Vacant => {}
+        }
+        let tunnel = match open_tunnel(
+            &args,
+            ports.as_ref(),
+            certs.as_ref(),
+            domain.clone(),
+            path.clone(),
+            port,
+        )
+        .await
         {
-            Ok(_r) => {
-                println!("response: {:?}", _r);
-                Some(OnShutdown {
-                    callback: callback.clone(),
-                    conn_id: conn_uid.clone(),
-                    domain: domain.clone(),
-                    path: path.clone(),
-                    port: listener.local_addr()?.port(),
-                })
+            Ok(tunnel) => tunnel,
+            Err(e) if e.is::<Exhausted>() => {
+                return busy(&mut wi, capabilities, e.to_string()).await
             }
-            Err(err) => {
-                error!("Error sending message to callback {:?}", err);
-                None
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::AddrInUse) =>
+            {
+                return busy(
+                    &mut wi,
+                    capabilities,
+                    format!("port {} is already taken", port.unwrap_or(0)),
+                )
+                .await;
             }
+            Err(e) => Err(e)?,
+        };
+        // None when another session won the race, claim its tunnel instead
+        if let Some(lease) = registry.register(tunnel, &client_key, tunnel_id.as_deref()) {
+            break (lease, true);
+        }
+    };
+    let tunnel = lease.tunnel.clone();
+    // only a tunnel that made it into the registry is announced, a lost race
+    // must not report one that never served anything
+    if let (true, Some(cb)) = (opened, &args.callback) {
+        let port = tunnel.addr.port();
+        if let Some(on_shutdown) =
+            callback::announce(cb, &tunnel.conn_uid, &tunnel.domain, &tunnel.path, port).await
+        {
+            tunnel.on_shutdown(on_shutdown);
         }
+    }
+    debug!(
+        "handshake complete waiting uid={}, port={}",
+        tunnel.conn_uid,
+        tunnel.addr.port()
+    );
+
+    wi.send(Message::ServerHello {
+        version: PROTOCOL_VERSION,
+        capabilities,
+        domain: tunnel.domain.clone(),
+        path: tunnel.path.clone(),
+        port: tunnel.addr.port(),
+        bind: tunnel.addr.ip().to_string(),
+        url: args.public_url.as_ref().map(|url| tunnel.public_url(url)),
+        role: lease.role,
+    })
+    .await?;
+
+    let mut heartbeat = if capabilities.contains(Capabilities::HEARTBEAT) {
+        Heartbeat::new(
+            Duration::from_secs(args.heartbeat_interval),
+            Duration::from_secs(args.heartbeat_timeout),
+        )
     } else {
-        None
+        Heartbeat::disabled()
     };
-    wi.send(Message::ServerHello { domain, path }).await?;
 
     loop {
         tokio::select! {
-            conn = listener.accept() => {
-                let (conn, sock) = conn?;
+            event = lease.next() => {
+                let ((conn, sock), active) = match event {
+                    Event::Incoming(conn, active) => (conn, active),
+                    Event::Kicked(message) => {
+                        info!("shutdown session, {}", message);
+                        // the old session is most likely dead, do not wait on it for long
+                        let shutdown = wi.send(Message::Shutdown { message: Some(message) });
+                        let _ = tokio::time::timeout(Duration::from_secs(1), shutdown).await;
+                        break;
+                    }
+                    Event::Promoted => {
+                        info!("session promoted to active");
+                        wi.send(Message::Promote).await?;
+                        continue;
+                    }
+                };
                 conn_id += 1;
                 debug!("new connection id={}, sock={:?}", conn_id, sock);
-                let (e2s_tx, e2s_rx) = tokio::sync::mpsc::channel::<Message>(32);
-                {
-                    let mut m = conn_map.lock().unwrap();
-                    m.insert(conn_id, e2s_tx);
-                }
+                let e2s_rx = conn_map.lock().unwrap().open(conn_id, capabilities);
                 wi.send(Message::NewConnection { id: conn_id }).await?;
                 let s2c_tx = s2c_tx.clone();
-                tokio::spawn(
-                    handle_connection(conn_id, e2s_rx, s2c_tx, conn)
-                );
+                tokio::spawn(async move {
+                    let _active = active;
+                    handle_connection(conn_id, e2s_rx, s2c_tx, conn, capabilities).await
+                });
             }
-            // message = c2s_rx.recv() => {
-            //     println!("rx1 completed first with {:?}", message);
-            // }
             message = s2c_rx.recv() => {
                 debug!("message from client: {:?}", message);
                 let message = message.ok_or(anyhow::anyhow!("no message found"))?;
-                wi.send(message).await?;
+                match message {
+                    Message::CloseConnection{id, reason} => {
+                        // not echoed back when the client closed it first
+                        let removed = conn_map.lock().unwrap().remove(id).is_some();
+                        if removed {
+                            wi.send(Message::CloseConnection { id, reason }).await?;
+                        }
+                    }
+                    Message::WindowUpdate{id, bytes} => {
+                        conn_map.lock().unwrap().grant(id, bytes);
+                        wi.send(message).await?;
+                    }
+                    _ => wi.send(message).await?,
+                }
             }
             message = ri.try_next() => {
                 debug!("message from external port: {:?}", message);
                 let message = message?.ok_or(anyhow::anyhow!("no message found"))?;
                 match message {
-                    Message::Data{id, data} => {
-                        let tx = match conn_map.lock().unwrap().get(&id) {
-                            Some(tx) => tx.clone(),
-                            None => Err(anyhow::anyhow!("key not found in conn map"))?,
-                        };
-                        tx.send(Message::Data {id, data}).await?;
+                    Message::Data{id, ..} | Message::WindowUpdate{id, ..} | Message::ShutdownWrite{id} => {
+                        let route = conn_map.lock().unwrap().route(id, message);
+                        match route {
+                            Route::Queued => {}
+                            // racing with our own close
+                            Route::Closed(message) => {
+                                debug!(
+                                    "drop {:?} for closed connection, stray frames={}",
+                                    message,
+                                    conn_map.lock().unwrap().stray_frames()
+                                );
+                            }
+                            Route::Unknown(message) => {
+                                warn!("{:?} for connection never opened", message);
+                                wi.send(Message::CloseConnection {
+                                    id,
+                                    reason: CloseReason::PolicyReject,
+                                })
+                                .await?;
+                            }
+                            Route::Overrun => {
+                                warn!("connection id={} sent more than its window, reset", id);
+                                wi.send(Message::CloseConnection {
+                                    id,
+                                    reason: CloseReason::PolicyReject,
+                                })
+                                .await?;
+                            }
+                        }
+                    },
+                    Message::CloseConnection {id, reason} => {
+                        let tx = conn_map.lock().unwrap().remove(id);
+                        if let Some(tx) = tx {
+                            // a full queue drops the channel instead, which resets
+                            let _ = tx.try_send(Message::CloseConnection { id, reason });
+                        }
                     },
                     Message::Shutdown {message} => {
                         info!("shutdown {:?}", message);
                         break;
                     },
-                    _ => break,
+                    Message::Ping {seq} => {
+                        wi.send(Message::Pong {seq}).await?;
+                    },
+                    Message::Pong {seq} => {
+                        heartbeat.pong(seq);
+                    },
+                    _ => {
+                        Err(anyhow::anyhow!("unknown message type from client"))?;
+                    }
                 }
             }
+            ping = heartbeat.tick() => {
+                wi.send(ping?).await?;
+            }
         }
     }
 
@@ -0,0 +1,71 @@
+use crate::protocol::ExternalMessage;
+use tracing::{debug, error};
+
+async fn post(callback: String, message: ExternalMessage) -> reqwest::Result<reqwest::Response> {
+    let client = reqwest::Client::new();
+    client
+        .post(callback)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&message).unwrap())
+        .send()
+        .await
+}
+
+// Tells the callback that the tunnel is gone when dropped.
+pub struct OnShutdown {
+    callback: String,
+    conn_id: String,
+    domain: String,
+    path: String,
+    port: u16,
+}
+
+impl Drop for OnShutdown {
+    fn drop(&mut self) {
+        let callback = self.callback.clone();
+        let message = ExternalMessage::ShutdownConnection {
+            conn_id: self.conn_id.clone(),
+            domain: self.domain.clone(),
+            path: self.path.clone(),
+            port: self.port,
+        };
+        tokio::spawn(async move {
+            if let Err(err) = post(callback, message).await {
+                error!("Error sending message to callback {:?}", err);
+            }
+        });
+    }
+}
+
+// Tells the callback about a new tunnel. The returned guard reports its
+// shutdown, None if the callback could not be reached.
+pub async fn announce(
+    callback: &str,
+    conn_id: &str,
+    domain: &str,
+    path: &str,
+    port: u16,
+) -> Option<OnShutdown> {
+    let message = ExternalMessage::NewConnection {
+        conn_id: conn_id.to_string(),
+        domain: domain.to_string(),
+        path: path.to_string(),
+        port,
+    };
+    match post(callback.to_string(), message).await {
+        Ok(response) => {
+            debug!("callback response: {:?}", response);
+            Some(OnShutdown {
+                callback: callback.to_string(),
+                conn_id: conn_id.to_string(),
+                domain: domain.to_string(),
+                path: path.to_string(),
+                port,
+            })
+        }
+        Err(err) => {
+            error!("Error sending message to callback {:?}", err);
+            None
+        }
+    }
+}
@@ -1,170 +1,125 @@
-use chacha20::{
-    cipher::{
-        typenum::{UInt, UTerm, B0, B1},
-        KeyIvInit, StreamCipher, StreamCipherCoreWrapper,
-    },
-    ChaCha20, ChaChaCore,
-};
-use futures::ready;
-use std::{
-    io,
-    pin::Pin,
-    task::{Context, Poll},
-};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-
-type Cipher = StreamCipherCoreWrapper<ChaChaCore<UInt<UInt<UInt<UInt<UTerm, B1>, B0>, B1>, B0>>>;
-
-// ----------------------------------------------------------------------------
-// decoder
-// ----------------------------------------------------------------------------
-pub struct DecStream<R: AsyncRead + Unpin> {
-    reader: R,
-    // StreamCipherCoreWrapper<ChaCha20> not working..
-    cipher: Cipher,
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+// Length delimited frames whose payload is sealed with ChaCha20-Poly1305.
+// The nonce of each frame is the session nonce xored with a frame counter, so
+// dropped, reordered or replayed frames fail authentication as well.
+pub struct SealedCodec {
+    inner: LengthDelimitedCodec,
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; 12],
+    counter: u64,
 }
 
-impl<R: AsyncRead + Unpin> DecStream<R> {
-    pub fn new(reader: R, key: &[u8; 32], nonce: &[u8; 12]) -> DecStream<R> {
-        DecStream {
-            reader,
-            cipher: ChaCha20::new(key.into(), nonce.into()),
+impl SealedCodec {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12]) -> SealedCodec {
+        SealedCodec {
+            inner: LengthDelimitedCodec::new(),
+            cipher: ChaCha20Poly1305::new(key.into()),
+            nonce: *nonce,
+            counter: 0,
         }
     }
-}
 
-impl<R: AsyncRead + Unpin> AsyncRead for DecStream<R> {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<io::Result<()>> {
-        ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
-        tracing::trace!(
-            "read<[raw] {}[bytes] {:?}",
-            buf.filled().len(),
-            &buf.filled()[0..std::cmp::min(buf.filled().len(), 128)]
-        );
-        if buf.filled().len() > 0 {
-            self.cipher.apply_keystream(buf.filled_mut());
-        }
-        tracing::trace!(
-            "read<[encrypted] {}[bytes] {:?}",
-            buf.filled().len(),
-            &buf.filled()[0..std::cmp::min(buf.filled().len(), 128)]
-        );
-        Poll::Ready(Ok(()))
+    fn next_nonce(&mut self) -> io::Result<Nonce> {
+        let mut nonce = self.nonce;
+        nonce[4..]
+            .iter_mut()
+            .zip(self.counter.to_be_bytes())
+            .for_each(|(n, c)| *n ^= c);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(io::Error::other("frame counter exhausted"))?;
+        Ok(nonce.into())
     }
 }
 
-// ----------------------------------------------------------------------------
-// encoder
-// ----------------------------------------------------------------------------
-pub struct EncStream<W: AsyncWrite + Unpin> {
-    writer: W,
-    cipher: Cipher,
-    start: usize,
-    end: usize,
-    buffer: Option<Vec<u8>>,
-}
-
-impl<W: AsyncWrite + Unpin> EncStream<W> {
-    pub fn new(writer: W, key: &[u8; 32], nonce: &[u8; 12]) -> EncStream<W> {
-        EncStream {
-            writer,
-            cipher: ChaCha20::new(key.into(), nonce.into()),
-            start: 0,
-            end: 0,
-            buffer: Some(vec![0u8; 4096]),
-        }
-    }
-}
-
-impl<W: AsyncWrite + Unpin> EncStream<W> {
-    fn poll_write_inner(
-        &mut self, // mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-        inner_buffer: &mut Vec<u8>,
-    ) -> Poll<Result<usize, io::Error>> {
-        let s = std::cmp::min(buf.len(), inner_buffer.len() - self.end);
-
-        tracing::trace!("write<[raw] {:?}", &buf[..s]);
-        inner_buffer[self.end..self.end + s].copy_from_slice(&buf[..s]);
-        self.cipher
-            .apply_keystream(&mut inner_buffer[self.end..self.end + s]);
-        self.end += s;
-
-        if self.start < self.end {
-            tracing::trace!(
-                "write<[encrypted] {:?}",
-                &inner_buffer[self.start..self.end]
-            );
-            let n = ready!(
-                Pin::new(&mut self.writer).poll_write(cx, &inner_buffer[self.start..self.end])
-            )?;
-            self.start += n;
-        }
-        if self.start >= self.end {
-            self.start = 0;
-            self.end = 0;
-        }
-
-        Poll::Ready(Ok(s))
+impl Decoder for SealedCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let frame = match self.inner.decode(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let nonce = self.next_nonce()?;
+        let plain = self.cipher.decrypt(&nonce, frame.as_ref()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "frame authentication failed")
+        })?;
+        tracing::trace!("read<[opened] {}[bytes]", plain.len());
+        Ok(Some(BytesMut::from(&plain[..])))
     }
 }
 
-impl<W: AsyncWrite + Unpin> AsyncWrite for EncStream<W> {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, io::Error>> {
-        let mut inner_buffer = self.buffer.take();
-        let res = self.poll_write_inner(cx, buf, inner_buffer.as_mut().unwrap());
-        self.buffer = inner_buffer;
-        res
-    }
-
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        ready!(Pin::new(&mut self.writer).poll_flush(cx))?;
-        Poll::Ready(Ok(()))
-    }
-
-    fn poll_shutdown(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Result<(), io::Error>> {
-        ready!(Pin::new(&mut self.writer).poll_shutdown(cx))?;
-        Poll::Ready(Ok(()))
+impl Encoder<Bytes> for SealedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let nonce = self.next_nonce()?;
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, item.as_ref())
+            .map_err(|_| io::Error::other("frame encryption failed"))?;
+        tracing::trace!("write<[sealed] {}[bytes]", item.len());
+        self.inner.encode(Bytes::from(sealed), dst)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::AsyncWriteExt;
 
-    #[tokio::test]
-    async fn enc() -> anyhow::Result<()> {
-        let mut buffer = Vec::new();
-        let mut writer = io::Cursor::new(&mut buffer);
+    fn seal(codec: &mut SealedCodec, data: &'static [u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from_static(data), &mut buf).unwrap();
+        buf
+    }
 
+    #[test]
+    fn sealed_roundtrip() {
         let key = [0x42; 32];
         let nonce = [0x24; 12];
+        let mut enc = SealedCodec::new(&key, &nonce);
+        let mut dec = SealedCodec::new(&key, &nonce);
 
-        {
-            let mut enc = EncStream::new(&mut writer, &key, &nonce);
-            enc.write(b"hogehoge").await?;
-        }
+        let mut buf = seal(&mut enc, b"hogehoge");
+        buf.extend_from_slice(&seal(&mut enc, b"fugafuga"));
 
-        println!("{:?}", buffer);
+        assert_eq!(&dec.decode(&mut buf).unwrap().unwrap()[..], b"hogehoge");
+        assert_eq!(&dec.decode(&mut buf).unwrap().unwrap()[..], b"fugafuga");
+        assert!(dec.decode(&mut buf).unwrap().is_none());
+    }
 
-        let mut cipher = ChaCha20::new(&key.into(), &nonce.into());
-        let mut b = buffer.clone();
-        cipher.apply_keystream(&mut b);
-        println!("{:?}", String::from_utf8(b));
+    #[test]
+    fn sealed_rejects_tampering() {
+        let key = [0x42; 32];
+        let nonce = [0x24; 12];
+        let mut enc = SealedCodec::new(&key, &nonce);
+        let mut dec = SealedCodec::new(&key, &nonce);
+
+        let mut buf = seal(&mut enc, b"hogehoge");
+        buf[6] ^= 0x01;
+        assert_eq!(
+            dec.decode(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn sealed_rejects_replay() {
+        let key = [0x42; 32];
+        let nonce = [0x24; 12];
+        let mut enc = SealedCodec::new(&key, &nonce);
+        let mut dec = SealedCodec::new(&key, &nonce);
+
+        let frame = seal(&mut enc, b"hogehoge");
+        let mut buf = frame.clone();
+        buf.extend_from_slice(&frame);
+        assert!(dec.decode(&mut buf).unwrap().is_some());
+        assert!(dec.decode(&mut buf).is_err());
     }
 }
@@ -0,0 +1,226 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Noise IK style handshake. The client knows the server's static public key
+// in advance and sends its own static public key sealed under the ephemeral
+// to static secret, so only the server learns who is connecting.
+//
+//   -> e, es, s, ss
+//   <- e, ee, se
+//
+// Session keys mix all four DH results, so the first sealed frame from the
+// client also proves possession of its static secret.
+
+const PROTOCOL_NAME: &[u8] = b"revconn_IK_25519_ChaChaPoly_SHA256";
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+// Keys and nonces for one session, one pair per direction.
+pub struct SessionKeys {
+    pub c2s_key: [u8; 32],
+    pub c2s_nonce: [u8; 12],
+    pub s2c_key: [u8; 32],
+    pub s2c_nonce: [u8; 12],
+}
+
+fn expand(hk: &Hkdf<Sha256>, info: &[u8]) -> ([u8; 32], [u8; 12]) {
+    let mut okm = [0u8; 44];
+    hk.expand(info, &mut okm)
+        .expect("44 bytes is a valid length for hkdf-sha256");
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    key.copy_from_slice(&okm[..32]);
+    nonce.copy_from_slice(&okm[32..]);
+    (key, nonce)
+}
+
+fn identity_key(server: &PublicKey, ephemeral: &PublicKey, es: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hash: [u8; 32] = Sha256::new()
+        .chain_update(PROTOCOL_NAME)
+        .chain_update(server.as_bytes())
+        .chain_update(ephemeral.as_bytes())
+        .finalize()
+        .into();
+    let (key, _) = expand(&Hkdf::<Sha256>::new(Some(&hash), es), b"revconn identity");
+    (hash, key)
+}
+
+struct Derived {
+    keys: SessionKeys,
+    confirm_key: [u8; 32],
+}
+
+fn derive(
+    hash: &[u8; 32],
+    message: &[u8],
+    server_ephemeral: &PublicKey,
+    dh: [[u8; 32]; 4],
+) -> Derived {
+    let hash: [u8; 32] = Sha256::new()
+        .chain_update(hash)
+        .chain_update(message)
+        .chain_update(server_ephemeral.as_bytes())
+        .finalize()
+        .into();
+    let hk = Hkdf::<Sha256>::new(Some(&hash), &dh.concat());
+    let (confirm_key, _) = expand(&hk, b"revconn confirm");
+    let (c2s_key, c2s_nonce) = expand(&hk, b"revconn client to server");
+    let (s2c_key, s2c_nonce) = expand(&hk, b"revconn server to client");
+    Derived {
+        keys: SessionKeys {
+            c2s_key,
+            c2s_nonce,
+            s2c_key,
+            s2c_nonce,
+        },
+        confirm_key,
+    }
+}
+
+fn seal(key: &[u8; 32], plain: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&[0u8; 12].into(), plain)
+        .expect("sealing a short message does not fail")
+}
+
+fn open(key: &[u8; 32], sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&[0u8; 12].into(), sealed)
+        .map_err(|_| anyhow::anyhow!("handshake authentication failed"))
+}
+
+pub async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &StaticSecret,
+    server: &PublicKey,
+) -> anyhow::Result<SessionKeys> {
+    let ephemeral = StaticSecret::random_from_rng(rand::thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let es = ephemeral.diffie_hellman(server).to_bytes();
+    let ss = identity.diffie_hellman(server).to_bytes();
+    let (hash, key) = identity_key(server, &ephemeral_public, &es);
+
+    let mut message = ephemeral_public.as_bytes().to_vec();
+    message.extend(seal(&key, PublicKey::from(identity).as_bytes()));
+    stream.write_all(&message).await?;
+
+    let mut response = [0u8; KEY_LEN + TAG_LEN];
+    stream.read_exact(&mut response).await?;
+    let mut server_ephemeral = [0u8; KEY_LEN];
+    server_ephemeral.copy_from_slice(&response[..KEY_LEN]);
+    let server_ephemeral = PublicKey::from(server_ephemeral);
+
+    let ee = ephemeral.diffie_hellman(&server_ephemeral).to_bytes();
+    let se = identity.diffie_hellman(&server_ephemeral).to_bytes();
+    let derived = derive(&hash, &message, &server_ephemeral, [es, ss, ee, se]);
+    open(&derived.confirm_key, &response[KEY_LEN..])?;
+
+    Ok(derived.keys)
+}
+
+// Runs the server side of the handshake and returns the session keys together
+// with the static public key of the client. Clients that are not listed in
+// `authorized` are rejected before anything is sent back.
+pub async fn server_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &StaticSecret,
+    authorized: &[PublicKey],
+) -> anyhow::Result<(SessionKeys, PublicKey)> {
+    let mut message = [0u8; KEY_LEN + KEY_LEN + TAG_LEN];
+    stream.read_exact(&mut message).await?;
+    let mut client_ephemeral = [0u8; KEY_LEN];
+    client_ephemeral.copy_from_slice(&message[..KEY_LEN]);
+    let client_ephemeral = PublicKey::from(client_ephemeral);
+
+    let es = identity.diffie_hellman(&client_ephemeral).to_bytes();
+    let (hash, key) = identity_key(&PublicKey::from(identity), &client_ephemeral, &es);
+    let client: [u8; KEY_LEN] = open(&key, &message[KEY_LEN..])?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid client identity"))?;
+    let client = PublicKey::from(client);
+    if !authorized.contains(&client) {
+        Err(anyhow::anyhow!("client is not authorized"))?;
+    }
+    let ss = identity.diffie_hellman(&client).to_bytes();
+
+    let ephemeral = StaticSecret::random_from_rng(rand::thread_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let ee = ephemeral.diffie_hellman(&client_ephemeral).to_bytes();
+    let se = ephemeral.diffie_hellman(&client).to_bytes();
+    let derived = derive(&hash, &message, &ephemeral_public, [es, ss, ee, se]);
+
+    let mut response = ephemeral_public.as_bytes().to_vec();
+    response.extend(seal(&derived.confirm_key, &[]));
+    stream.write_all(&response).await?;
+
+    Ok((derived.keys, client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    #[tokio::test]
+    async fn handshake() -> anyhow::Result<()> {
+        let (server, server_public) = keypair();
+        let (client, client_public) = keypair();
+        let authorized = [client_public];
+        let (mut c, mut s) = tokio::io::duplex(1024);
+
+        let (ck, (sk, peer)) = tokio::try_join!(
+            client_handshake(&mut c, &client, &server_public),
+            server_handshake(&mut s, &server, &authorized)
+        )?;
+
+        assert_eq!(peer, client_public);
+        assert_eq!(ck.c2s_key, sk.c2s_key);
+        assert_eq!(ck.c2s_nonce, sk.c2s_nonce);
+        assert_eq!(ck.s2c_key, sk.s2c_key);
+        assert_eq!(ck.s2c_nonce, sk.s2c_nonce);
+        assert_ne!(ck.c2s_key, ck.s2c_key);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unauthorized_client() {
+        let (server, server_public) = keypair();
+        let (client, _) = keypair();
+        let (_, other_public) = keypair();
+        let (mut c, mut s) = tokio::io::duplex(1024);
+
+        let (cr, sr) = tokio::join!(client_handshake(&mut c, &client, &server_public), async {
+            let r = server_handshake(&mut s, &server, &[other_public]).await;
+            drop(s);
+            r
+        });
+        assert!(cr.is_err());
+        assert!(sr.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_server_key() {
+        let (server, _) = keypair();
+        let (_, other_public) = keypair();
+        let (client, client_public) = keypair();
+        let (mut c, mut s) = tokio::io::duplex(1024);
+
+        let (cr, sr) = tokio::join!(client_handshake(&mut c, &client, &other_public), async {
+            let r = server_handshake(&mut s, &server, &[client_public]).await;
+            drop(s);
+            r
+        });
+        assert!(cr.is_err());
+        assert!(sr.is_err());
+    }
+}
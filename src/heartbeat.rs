@@ -0,0 +1,85 @@
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::protocol::Message;
+
+// Sends a Ping every `interval` and declares the peer dead when no Pong has
+// arrived for `timeout`. A disabled heartbeat never ticks.
+pub struct Heartbeat {
+    interval: Option<Interval>,
+    timeout: Duration,
+    seq: u64,
+    last_pong: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Heartbeat {
+        let mut interval = tokio::time::interval_at(Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Heartbeat {
+            interval: Some(interval),
+            timeout,
+            seq: 0,
+            last_pong: Instant::now(),
+        }
+    }
+
+    pub fn disabled() -> Heartbeat {
+        Heartbeat {
+            interval: None,
+            timeout: Duration::MAX,
+            seq: 0,
+            last_pong: Instant::now(),
+        }
+    }
+
+    // Resolves with the next Ping to send, or an error once the peer has
+    // missed the timeout.
+    pub async fn tick(&mut self) -> anyhow::Result<Message> {
+        match self.interval.as_mut() {
+            Some(interval) => interval.tick().await,
+            None => std::future::pending().await,
+        };
+        if self.last_pong.elapsed() > self.timeout {
+            Err(anyhow::anyhow!(
+                "no pong received for {:?}, peer is dead",
+                self.last_pong.elapsed()
+            ))?;
+        }
+        self.seq += 1;
+        Ok(Message::Ping { seq: self.seq })
+    }
+
+    pub fn pong(&mut self, seq: u64) {
+        tracing::trace!("pong seq={}, rtt<={:?}", seq, self.last_pong.elapsed());
+        self.last_pong = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_peer() -> anyhow::Result<()> {
+        let step = Duration::from_millis(10);
+        let mut hb = Heartbeat::new(step, Duration::from_millis(25));
+        tokio::time::advance(step).await;
+        assert!(matches!(hb.tick().await?, Message::Ping { seq: 1 }));
+        hb.pong(1);
+        tokio::time::advance(step).await;
+        assert!(matches!(hb.tick().await?, Message::Ping { seq: 2 }));
+        tokio::time::advance(step).await;
+        assert!(matches!(hb.tick().await?, Message::Ping { seq: 3 }));
+        tokio::time::advance(step).await;
+        assert!(hb.tick().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled() {
+        let mut hb = Heartbeat::disabled();
+        let r = tokio::time::timeout(Duration::from_secs(3600), hb.tick()).await;
+        assert!(r.is_err());
+    }
+}
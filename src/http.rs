@@ -0,0 +1,287 @@
+use crate::{
+    protocol::CloseReason,
+    stream::{Conn, Prefixed},
+    tunnel::Registry,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+// Longest request head we wait for before giving up on a connection.
+const MAX_HEAD: usize = 16 * 1024;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestHead {
+    // None for HTTP/1.0 requests without a Host header
+    pub host: Option<String>,
+    pub path: String,
+}
+
+// Host and path of an HTTP/1 request, None while the head is incomplete.
+pub fn parse_head(buf: &[u8]) -> anyhow::Result<Option<RequestHead>> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buf[..end])?;
+    let mut lines = head.split("\r\n");
+
+    let request = lines.next().unwrap_or_default();
+    let mut parts = request.split(' ');
+    let (target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_method), Some(target), Some(version), None) => (target, version),
+        _ => anyhow::bail!("invalid request line {:?}", request),
+    };
+    if !version.starts_with("HTTP/") {
+        anyhow::bail!("invalid request line {:?}", request);
+    }
+
+    // absolute form, as sent to proxies
+    let (mut host, path) = match target.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(i) => (Some(&rest[..i]), &rest[i..]),
+            None => (Some(rest), "/"),
+        },
+        None => (None, target),
+    };
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("host") {
+                host = host.or(Some(value.trim()));
+            }
+        }
+    }
+
+    // drop the port, IPv6 literals keep their brackets
+    let host = host.map(|host| match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    });
+    Ok(Some(RequestHead {
+        host: host.map(|host| host.to_ascii_lowercase()),
+        path: path.to_string(),
+    }))
+}
+
+// The request with its head asking the backend to close the connection after
+// the response. Only the first request of a connection is routed, the client
+// has to open a new one for the next request to be routed on its own. An
+// upgrade, e.g. to a WebSocket, stays with the backend and is left alone.
+pub fn close_after_response(buf: &[u8]) -> Vec<u8> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return buf.to_vec(),
+    };
+    let head = match std::str::from_utf8(&buf[..end]) {
+        Ok(head) => head,
+        Err(_) => return buf.to_vec(),
+    };
+    let mut rewritten = String::new();
+    for line in head.split("\r\n") {
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("connection") {
+                if value.to_ascii_lowercase().contains("upgrade") {
+                    return buf.to_vec();
+                }
+                continue;
+            }
+            if name.eq_ignore_ascii_case("keep-alive") {
+                continue;
+            }
+        }
+        rewritten.push_str(line);
+        rewritten.push_str("\r\n");
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    [rewritten.as_bytes(), &buf[end + 4..]].concat()
+}
+
+pub fn page(status: u16) -> Vec<u8> {
+    let reason = match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        502 => "Bad Gateway",
+        _ => "Error",
+    };
+    let body = format!(
+        "<html><head><title>{0} {1}</title></head><body><h1>{0} {1}</h1></body></html>\n",
+        status, reason
+    );
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn bad_gateway(_reason: CloseReason) -> Vec<u8> {
+    page(502)
+}
+
+async fn read_head<C: Conn>(conn: &mut C, buf: &mut Vec<u8>) -> anyhow::Result<RequestHead> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(head) = parse_head(buf)? {
+            return Ok(head);
+        }
+        if buf.len() >= MAX_HEAD {
+            anyhow::bail!("request head longer than {} bytes", MAX_HEAD);
+        }
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("closed before the request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Route a connection on the shared HTTP port by the Host header and path of
+// its request. The connection closes after the response so that every
+// request is routed on its own, backends that cannot be reached are answered
+// with 502.
+pub async fn serve<C: Conn + 'static>(
+    mut conn: C,
+    addr: SocketAddr,
+    registry: Arc<Registry>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    let head = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut conn, &mut buf)).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            debug!("bad request from {}, {}", addr, e);
+            conn.write_all(&page(400)).await?;
+            return Ok(conn.shutdown().await?);
+        }
+        Err(_) => anyhow::bail!("timeout reading the request head from {}", addr),
+    };
+
+    let host = head.host.as_deref().unwrap_or_default();
+    match registry.lookup(host, &head.path) {
+        Some(tunnel) => {
+            debug!(
+                "route {}{} from {} to port={}",
+                host,
+                head.path,
+                addr,
+                tunnel.addr.port()
+            );
+            let buf = close_after_response(&buf);
+            let conn = Prefixed::new(buf, conn).with_error_page(bad_gateway);
+            tunnel.dispatch((Box::new(conn), addr));
+        }
+        None => {
+            debug!("no tunnel for {}{} from {}", host, head.path, addr);
+            conn.write_all(&page(404)).await?;
+            conn.shutdown().await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::{Balance, Conflict, DispatchPolicy, Event, Tunnel};
+    use tokio::net::{TcpListener, TcpStream};
+    use x25519_dalek::PublicKey;
+
+    fn head(host: &str, path: &str) -> Option<RequestHead> {
+        Some(RequestHead {
+            host: Some(host.to_string()),
+            path: path.to_string(),
+        })
+    }
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        let request = b"GET /api?x=1 HTTP/1.1\r\nHOST: A.example:8080\r\n\r\nbody";
+        assert_eq!(parse_head(request)?, head("a.example", "/api?x=1"));
+        assert_eq!(parse_head(&request[..20])?, None);
+
+        let absolute = b"GET http://a.example HTTP/1.1\r\nHost: b.example\r\n\r\n";
+        assert_eq!(parse_head(absolute)?, head("a.example", "/"));
+        let ipv6 = b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n";
+        assert_eq!(parse_head(ipv6)?, head("[::1]", "/"));
+
+        let no_host = parse_head(b"GET / HTTP/1.0\r\n\r\n")?;
+        assert_eq!(no_host.and_then(|head| head.host), None);
+        assert!(parse_head(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn close() {
+        let request = b"POST / HTTP/1.1\r\nHost: a\r\nconnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\nbody";
+        assert_eq!(
+            close_after_response(request),
+            b"POST / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\nbody"
+        );
+        let upgrade =
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(close_after_response(upgrade), upgrade);
+    }
+
+    #[tokio::test]
+    async fn route() -> anyhow::Result<()> {
+        let registry = crate::tunnel::Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let tunnel = Tunnel::new(
+            "conn-test".to_string(),
+            "a.example".to_string(),
+            "/api".to_string(),
+            TcpListener::bind("127.0.0.1:0").await?,
+            None,
+            DispatchPolicy {
+                balance: Balance::RoundRobin,
+                queue_size: 8,
+                queue_timeout: Duration::from_secs(1),
+            },
+        )?;
+        let mut lease = registry
+            .register(tunnel, &PublicKey::from([1; 32]), None)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let shared = listener.local_addr()?;
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: a.example\r\n\r\n", path);
+
+        // the request reaches the tunnel, the backend closes after answering
+        // it so that the next one is routed again
+        let mut client = TcpStream::connect(shared).await?;
+        client.write_all(request("/api/users").as_bytes()).await?;
+        let (conn, addr) = listener.accept().await?;
+        serve(conn, addr, registry.clone()).await?;
+        let mut routed = match lease.next().await {
+            Event::Incoming((conn, _), _) => conn,
+            _ => panic!("not routed"),
+        };
+        let routed_request =
+            "GET /api/users HTTP/1.1\r\nHost: a.example\r\nConnection: close\r\n\r\n";
+        let mut buf = vec![0u8; routed_request.len()];
+        routed.read_exact(&mut buf).await?;
+        assert_eq!(buf, routed_request.as_bytes());
+
+        // nothing claims the path
+        let mut client = TcpStream::connect(shared).await?;
+        client.write_all(request("/other").as_bytes()).await?;
+        let (conn, addr) = listener.accept().await?;
+        serve(conn, addr, registry.clone()).await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // nor a request without a host
+        let mut client = TcpStream::connect(shared).await?;
+        client.write_all(b"GET /api HTTP/1.0\r\n\r\n").await?;
+        let (conn, addr) = listener.accept().await?;
+        serve(conn, addr, registry.clone()).await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+}
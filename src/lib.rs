@@ -1,3 +1,14 @@
+pub mod acl;
+pub mod backoff;
+pub mod callback;
 pub mod encstream;
+pub mod handshake;
+pub mod heartbeat;
+pub mod http;
+pub mod ports;
 pub mod protocol;
+pub mod sni;
+pub mod stream;
+pub mod tls;
+pub mod tunnel;
 pub mod util;
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::Notify};
+
+// An inclusive range of ports, written as 2000-2999 or a single 2222.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    pub fn count(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<PortRange> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let range = PortRange {
+            start: start.trim().parse()?,
+            end: end.trim().parse()?,
+        };
+        if range.start == 0 || range.start > range.end {
+            anyhow::bail!("invalid port range {}", s);
+        }
+        Ok(range)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+// Every port of the range is taken.
+#[derive(Debug)]
+pub struct Exhausted(pub PortRange);
+
+impl std::fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no free port left in {}", self.0)
+    }
+}
+
+impl std::error::Error for Exhausted {}
+
+struct Allocations {
+    used: HashSet<u16>,
+    next: u16,
+}
+
+// Hands out the ports of a range to exposed listeners. Ports are handed out
+// round robin so a port just released is not reused right away.
+#[derive(Clone)]
+pub struct Ports {
+    range: PortRange,
+    state: Arc<Mutex<Allocations>>,
+    released: Arc<Notify>,
+}
+
+impl Ports {
+    pub fn new(range: PortRange) -> Ports {
+        Ports {
+            range,
+            state: Arc::new(Mutex::new(Allocations {
+                used: HashSet::new(),
+                next: range.start,
+            })),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn range(&self) -> PortRange {
+        self.range
+    }
+
+    // Take a specific port, None if it is already handed out.
+    pub fn reserve(&self, port: u16) -> Option<PortLease> {
+        let mut state = self.state.lock().unwrap();
+        if !self.range.contains(port) || !state.used.insert(port) {
+            return None;
+        }
+        Some(PortLease {
+            port,
+            ports: self.clone(),
+        })
+    }
+
+    // Take a specific port, waiting up to `patience` for a lease on it that is
+    // about to be released, e.g. by a tunnel that was just dropped.
+    pub async fn reserve_within(&self, port: u16, patience: Duration) -> Option<PortLease> {
+        let deadline = tokio::time::Instant::now() + patience;
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(lease) = self.reserve(port) {
+                return Some(lease);
+            }
+            tokio::time::timeout_at(deadline, released).await.ok()?;
+        }
+    }
+
+    // Take the next free port.
+    pub fn allocate(&self) -> Result<PortLease, Exhausted> {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..self.range.count() {
+            let port = state.next;
+            state.next = if port == self.range.end {
+                self.range.start
+            } else {
+                port + 1
+            };
+            if state.used.insert(port) {
+                return Ok(PortLease {
+                    port,
+                    ports: self.clone(),
+                });
+            }
+        }
+        Err(Exhausted(self.range))
+    }
+
+    // Bind the next free port that is not in use by another process either.
+    pub fn bind(&self, ip: std::net::IpAddr) -> anyhow::Result<(TcpListener, PortLease)> {
+        for _ in 0..self.range.count() {
+            let lease = self.allocate()?;
+            match bind(SocketAddr::new(ip, lease.port)) {
+                Ok(listener) => return Ok((listener, lease)),
+                // taken by someone else, the lease moves on to the next port
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+                Err(e) => Err(e)?,
+            }
+        }
+        Err(Exhausted(self.range))?
+    }
+}
+
+// A port handed out by Ports, free again once dropped.
+pub struct PortLease {
+    pub port: u16,
+    ports: Ports,
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.ports.state.lock().unwrap().used.remove(&self.port);
+        self.ports.released.notify_waiters();
+    }
+}
+
+// Bind a listener, the unspecified IPv6 address accepts IPv4 as well.
+pub fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        let range: PortRange = "2000-2999".parse()?;
+        assert_eq!(
+            range,
+            PortRange {
+                start: 2000,
+                end: 2999
+            }
+        );
+        assert!(range.contains(2000) && range.contains(2999));
+        assert!(!range.contains(1999) && !range.contains(3000));
+        assert_eq!("2222".parse::<PortRange>()?.to_string(), "2222-2222");
+
+        assert!("3000-2000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("2000-70000".parse::<PortRange>().is_err());
+        assert!("ssh".parse::<PortRange>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn allocate() -> anyhow::Result<()> {
+        let ports = Ports::new("2000-2002".parse()?);
+        let a = ports.allocate()?;
+        let b = ports.allocate()?;
+        let c = ports.allocate()?;
+        assert_eq!((a.port, b.port, c.port), (2000, 2001, 2002));
+        assert!(ports.allocate().is_err());
+        assert!(ports.reserve(2001).is_none());
+
+        drop(b);
+        assert_eq!(ports.allocate()?.port, 2001);
+        let b = ports.reserve(2001);
+        assert!(b.is_some());
+        assert!(ports.reserve(3000).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_skips_taken() -> anyhow::Result<()> {
+        // find two adjacent free ports and occupy the first one
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        let port = taken.local_addr()?.port();
+        let ports = Ports::new(PortRange {
+            start: port,
+            end: port.saturating_add(1),
+        });
+        match ports.bind("127.0.0.1".parse()?) {
+            Ok((listener, lease)) => {
+                assert_eq!(lease.port, port + 1);
+                assert_eq!(listener.local_addr()?.port(), port + 1);
+                assert!(ports.bind("127.0.0.1".parse()?).is_err());
+            }
+            // the neighbour was in use as well
+            Err(e) => assert!(e.is::<Exhausted>()),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dual_stack() -> anyhow::Result<()> {
+        let listener = match bind("[::]:0".parse()?) {
+            Ok(listener) => listener,
+            // no IPv6 in this environment
+            Err(_) => return Ok(()),
+        };
+        let port = listener.local_addr()?.port();
+        tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+        Ok(())
+    }
+}
@@ -1,14 +1,132 @@
 use serde::{Deserialize, Serialize};
 
+// Bump whenever the layout of an existing Message variant changes. New
+// variants are appended and only sent once the matching capability has been
+// negotiated. ClientHello, ServerHello and Reject must keep their position and
+// leading fields so that peers speaking another version can still be told why
+// they are turned away.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// Optional features, negotiated in the hello exchange. The server answers
+// with the subset both sides support.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 0);
+    pub const FLOW_CONTROL: Capabilities = Capabilities(1 << 1);
+    pub const HALF_CLOSE: Capabilities = Capabilities(1 << 2);
+    // understands Busy, conflicts are retried instead of ending the client
+    pub const BUSY: Capabilities = Capabilities(1 << 3);
+
+    // Everything this build implements.
+    pub fn supported() -> Capabilities {
+        Capabilities::HEARTBEAT
+            | Capabilities::FLOW_CONTROL
+            | Capabilities::HALF_CLOSE
+            | Capabilities::BUSY
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+// Why a tunneled connection was closed, so the far side can close its end
+// the same way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Eof,
+    ConnectRefused,
+    Timeout,
+    IoError,
+    PolicyReject,
+}
+
+impl CloseReason {
+    pub fn from_io_error(e: &std::io::Error) -> CloseReason {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => CloseReason::ConnectRefused,
+            std::io::ErrorKind::TimedOut => CloseReason::Timeout,
+            _ => CloseReason::IoError,
+        }
+    }
+
+    // Anything but a clean EOF is passed on as a TCP reset.
+    pub fn is_reset(self) -> bool {
+        self != CloseReason::Eof
+    }
+}
+
+// Whether a session gets the tunnel's traffic or waits for the active one to
+// drop.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Active,
+    Standby,
+}
+
+// As printed by the client for scripts to read.
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Role::Active => write!(f, "active"),
+            Role::Standby => write!(f, "standby"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Message {
     ClientHello {
+        version: u32,
+        capabilities: Capabilities,
         domain: String,
         path: Option<String>,
+        // identifies the client across reconnects, a new session with the same
+        // id takes over the old one instead of conflicting with it
+        tunnel_id: Option<String>,
+        // a fixed port to expose the tunnel on instead of any free one
+        port: Option<u16>,
+        // wait for traffic until the active session drops
+        standby: bool,
     },
     ServerHello {
+        version: u32,
+        capabilities: Capabilities,
         domain: String,
         path: String,
+        // where the tunnel is exposed
+        port: u16,
+        bind: String,
+        url: Option<String>,
+        role: Role,
+    },
+    Reject {
+        reason: String,
     },
     NewConnection {
         id: u32,
@@ -19,24 +137,70 @@ pub enum Message {
     },
     CloseConnection {
         id: u32,
+        reason: CloseReason,
     },
     Shutdown {
         message: Option<String>,
     },
+    Ping {
+        seq: u64,
+    },
+    Pong {
+        seq: u64,
+    },
+    WindowUpdate {
+        id: u32,
+        bytes: u32,
+    },
+    ShutdownWrite {
+        id: u32,
+    },
+    // the standby session is active now, only sent to clients that asked to
+    // stand by
+    Promote,
+    // sent instead of ServerHello while another session holds the tunnel,
+    // unlike Reject it is worth trying again later
+    Busy {
+        reason: String,
+    },
 }
 
 impl std::fmt::Debug for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Message::ClientHello { domain, path } => {
+            Message::ClientHello {
+                version,
+                capabilities,
+                domain,
+                path,
+                tunnel_id,
+                port,
+                standby,
+            } => {
+                write!(
+                    f,
+                    "Message::ClientHello version={}, capabilities={:?}, domain={:?}, path={:?}, tunnel_id={:?}, port={:?}, standby={}",
+                    version, capabilities, domain, path, tunnel_id, port, standby
+                )
+            }
+            Message::ServerHello {
+                version,
+                capabilities,
+                domain,
+                path,
+                port,
+                bind,
+                url,
+                role,
+            } => {
                 write!(
                     f,
-                    "Message::ClientHello domain={:?}, path={:?}",
-                    domain, path
+                    "Message::ServerHello version={}, capabilities={:?}, domain={}, path={}, port={}, bind={}, url={:?}, role={:?}",
+                    version, capabilities, domain, path, port, bind, url, role
                 )
             }
-            Message::ServerHello { domain, path } => {
-                write!(f, "Message::ServerHello domain={}, path={}", domain, path)
+            Message::Reject { reason } => {
+                write!(f, "Message::Reject reason={}", reason)
             }
             Message::NewConnection { id } => {
                 write!(f, "Message::NewConnection id={}", id)
@@ -44,12 +208,30 @@ impl std::fmt::Debug for Message {
             Message::Data { id, data } => {
                 write!(f, "Message::Data id={}, bytes={}", id, data.len())
             }
-            Message::CloseConnection { id } => {
-                write!(f, "Message::CloseConnection id={}", id)
+            Message::CloseConnection { id, reason } => {
+                write!(f, "Message::CloseConnection id={}, reason={:?}", id, reason)
             }
             Message::Shutdown { message } => {
                 write!(f, "Message::Shutdown message={:?}", message)
             }
+            Message::Ping { seq } => {
+                write!(f, "Message::Ping seq={}", seq)
+            }
+            Message::Pong { seq } => {
+                write!(f, "Message::Pong seq={}", seq)
+            }
+            Message::WindowUpdate { id, bytes } => {
+                write!(f, "Message::WindowUpdate id={}, bytes={}", id, bytes)
+            }
+            Message::ShutdownWrite { id } => {
+                write!(f, "Message::ShutdownWrite id={}", id)
+            }
+            Message::Promote => {
+                write!(f, "Message::Promote")
+            }
+            Message::Busy { reason } => {
+                write!(f, "Message::Busy reason={}", reason)
+            }
         }
     }
 }
@@ -70,3 +252,83 @@ pub enum ExternalMessage {
         port: u16,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encstream::SealedCodec;
+    use bincode::Options;
+    use futures::{SinkExt, TryStreamExt};
+    use tokio_serde::formats::SymmetricalBincode;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    #[test]
+    fn capabilities() {
+        let a = Capabilities(0b011);
+        let b = Capabilities(0b110);
+        assert_eq!(a.intersection(b), Capabilities(0b010));
+        assert_eq!(a | b, Capabilities(0b111));
+        assert!(a.contains(Capabilities(0b001)));
+        assert!(!a.contains(b));
+        assert!(a.contains(Capabilities::NONE));
+        assert_eq!(a.without(b), Capabilities(0b001));
+    }
+
+    #[test]
+    fn role() {
+        assert_eq!(Role::Active.to_string(), "active");
+        assert_eq!(Role::Standby.to_string(), "standby");
+    }
+
+    // A message as it goes on the wire, sealed and framed like a session does,
+    // then opened again to look at the plaintext.
+    async fn wire(message: Message) -> anyhow::Result<Vec<u8>> {
+        let (key, nonce) = ([7u8; 32], [9u8; 12]);
+        let mut sealed = vec![];
+        let mut wi = tokio_serde::SymmetricallyFramed::new(
+            FramedWrite::new(&mut sealed, SealedCodec::new(&key, &nonce)),
+            SymmetricalBincode::<Message>::default(),
+        );
+        wi.send(message).await?;
+        drop(wi);
+
+        let mut frames = FramedRead::new(&sealed[..], SealedCodec::new(&key, &nonce));
+        let frame = frames
+            .try_next()
+            .await?
+            .ok_or(anyhow::anyhow!("no frame"))?;
+        Ok(frame.to_vec())
+    }
+
+    #[tokio::test]
+    async fn hello_layout() -> anyhow::Result<()> {
+        // the version must stay readable at the same offset in every release,
+        // the variant tag and the version are varints taking a single byte
+        // below 251
+        const { assert!(PROTOCOL_VERSION < 251) };
+        let b = wire(Message::ClientHello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            domain: "example.com".to_string(),
+            path: None,
+            tunnel_id: None,
+            port: None,
+            standby: false,
+        })
+        .await?;
+        assert_eq!(b[0], 0);
+        assert_eq!(b[1], PROTOCOL_VERSION as u8);
+        match bincode::DefaultOptions::new().deserialize(&b)? {
+            Message::ClientHello { version, .. } => assert_eq!(version, PROTOCOL_VERSION),
+            message => panic!("decoded as {:?}", message),
+        }
+
+        let b = wire(Message::Reject {
+            reason: "busy".to_string(),
+        })
+        .await?;
+        assert_eq!(b, [2, 4, b'b', b'u', b's', b'y']);
+
+        Ok(())
+    }
+}
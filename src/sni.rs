@@ -0,0 +1,356 @@
+use crate::{stream::Prefixed, tunnel::Registry};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::debug;
+
+// Longest ClientHello we wait for, large post-quantum key shares included.
+const MAX_HELLO: usize = 16 * 1024;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Fatal unrecognized_name alert, sent when no tunnel serves the name.
+const UNRECOGNIZED_NAME: [u8; 7] = [21, 3, 1, 0, 2, 2, 112];
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < n {
+            anyhow::bail!("truncated ClientHello");
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<usize> {
+        Ok(self.take(1)?[0] as usize)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<usize> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> anyhow::Result<usize> {
+        let b = self.take(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+// The handshake messages carried by the complete TLS records at the start of
+// buf, None while the ClientHello is incomplete.
+fn handshake(buf: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut records = Reader(buf);
+    let mut handshake = vec![];
+    while records.0.len() >= 5 {
+        let header = records.take(5)?;
+        if header[0] != 22 || header[1] != 3 {
+            anyhow::bail!("not a TLS handshake");
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        match records.take(len) {
+            Ok(fragment) => handshake.extend_from_slice(fragment),
+            Err(_) => return Ok(None),
+        }
+        if handshake.len() >= 4 {
+            let mut r = Reader(&handshake);
+            let (kind, len) = (r.u8()?, r.u24()?);
+            if kind != 1 {
+                anyhow::bail!("not a ClientHello");
+            }
+            if handshake.len() >= 4 + len {
+                handshake.truncate(4 + len);
+                return Ok(Some(handshake));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Server name of the ClientHello at the start of buf, Some(None) if it has
+// none and None while it is incomplete.
+pub fn parse_sni(buf: &[u8]) -> anyhow::Result<Option<Option<String>>> {
+    let handshake = match handshake(buf)? {
+        Some(handshake) => handshake,
+        None => return Ok(None),
+    };
+    let mut r = Reader(&handshake[4..]);
+    r.take(2 + 32)?; // version, random
+    let n = r.u8()?;
+    r.take(n)?; // session id
+    let n = r.u16()?;
+    r.take(n)?; // cipher suites
+    let n = r.u8()?;
+    r.take(n)?; // compression methods
+    if r.0.is_empty() {
+        return Ok(Some(None));
+    }
+    let n = r.u16()?;
+    let mut extensions = Reader(r.take(n)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let n = extensions.u16()?;
+        let mut data = Reader(extensions.take(n)?);
+        if kind != 0 {
+            continue;
+        }
+        let n = data.u16()?;
+        let mut names = Reader(data.take(n)?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let n = names.u16()?;
+            let name = names.take(n)?;
+            if kind == 0 {
+                let name = std::str::from_utf8(name)?.trim_end_matches('.');
+                return Ok(Some(Some(name.to_ascii_lowercase())));
+            }
+        }
+    }
+    Ok(Some(None))
+}
+
+async fn read_sni(conn: &mut TcpStream, buf: &mut Vec<u8>) -> anyhow::Result<Option<String>> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(sni) = parse_sni(buf)? {
+            return Ok(sni);
+        }
+        if buf.len() >= MAX_HELLO {
+            anyhow::bail!("ClientHello longer than {} bytes", MAX_HELLO);
+        }
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("closed before the ClientHello");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Route a TLS connection on the shared port by the server name of its
+// ClientHello. TLS is only terminated for tunnels with a certificate, others
+// receive the stream as sent, ClientHello included.
+pub async fn serve(
+    mut conn: TcpStream,
+    addr: SocketAddr,
+    registry: Arc<Registry>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    let sni = match tokio::time::timeout(HELLO_TIMEOUT, read_sni(&mut conn, &mut buf)).await {
+        Ok(sni) => sni?,
+        Err(_) => anyhow::bail!("timeout reading the ClientHello from {}", addr),
+    };
+
+    match sni.as_ref().and_then(|sni| registry.lookup(sni, "/")) {
+        Some(tunnel) => {
+            debug!(
+                "route {} from {} to port={}",
+                tunnel.domain,
+                addr,
+                tunnel.addr.port()
+            );
+            tunnel.accept((Box::new(Prefixed::new(buf, conn)), addr));
+        }
+        None => {
+            debug!("no tunnel for server name {:?} from {}", sni, addr);
+            conn.write_all(&UNRECOGNIZED_NAME).await?;
+            conn.shutdown().await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tls::Certs,
+        tunnel::{Balance, Conflict, DispatchPolicy, Event, Listener, Tunnel},
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            self,
+            client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            crypto::{ring, verify_tls12_signature, verify_tls13_signature},
+            pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+            DigitallySignedStruct, SignatureScheme,
+        },
+        TlsConnector,
+    };
+    use x25519_dalek::PublicKey;
+
+    // Trusts exactly the test certificate, it has no subject alt name for
+    // the usual checks.
+    #[derive(Debug)]
+    struct Pinned(CertificateDer<'static>);
+
+    impl ServerCertVerifier for Pinned {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            if *end_entity != self.0 {
+                return Err(rustls::Error::General("unexpected certificate".to_string()));
+            }
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            let algorithms = ring::default_provider().signature_verification_algorithms;
+            verify_tls12_signature(message, cert, dss, &algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            let algorithms = ring::default_provider().signature_verification_algorithms;
+            verify_tls13_signature(message, cert, dss, &algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    // A minimal ClientHello, split into records of at most `fragment` bytes.
+    fn client_hello(name: Option<&str>, fragment: usize) -> Vec<u8> {
+        let mut extensions = vec![];
+        if let Some(name) = name {
+            let name = name.as_bytes();
+            let list = [&[0u8][..], &(name.len() as u16).to_be_bytes(), name].concat();
+            let data = [&(list.len() as u16).to_be_bytes()[..], &list].concat();
+            extensions.extend_from_slice(&[0, 0]);
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&data);
+        }
+        // an unrelated extension, supported_versions
+        extensions.extend_from_slice(&[0, 43, 0, 3, 2, 3, 4]);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        handshake
+            .chunks(fragment)
+            .flat_map(|chunk| {
+                let mut record = vec![22, 3, 1];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        let hello = client_hello(Some("A.example."), 1024);
+        assert_eq!(parse_sni(&hello)?, Some(Some("a.example".to_string())));
+        assert_eq!(parse_sni(&hello[..hello.len() - 1])?, None);
+        assert_eq!(parse_sni(&hello[..3])?, None);
+
+        let fragmented = client_hello(Some("a.example"), 16);
+        assert_eq!(parse_sni(&fragmented)?, Some(Some("a.example".to_string())));
+        assert_eq!(parse_sni(&client_hello(None, 1024))?, Some(None));
+
+        assert!(parse_sni(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn terminate() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("revconn-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir)?;
+        let (cert, key) = (dir.join("a.crt"), dir.join("a.key"));
+        std::fs::write(&cert, include_str!("../testdata/a1.crt"))?;
+        std::fs::write(&key, include_str!("../testdata/a1.key"))?;
+        let spec = format!("a.example={},{}", cert.display(), key.display());
+        let certs = Certs::load(vec![spec.parse()?])?;
+        std::fs::remove_dir_all(dir)?;
+
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let tunnel = Tunnel::new(
+            "conn-test".to_string(),
+            "a.example".to_string(),
+            "/".to_string(),
+            Listener {
+                tcp: TcpListener::bind("127.0.0.1:0").await?,
+                tls: certs.acceptor("a.example"),
+            },
+            None,
+            DispatchPolicy {
+                balance: Balance::RoundRobin,
+                queue_size: 8,
+                queue_timeout: Duration::from_secs(1),
+            },
+        )?;
+        let own = tunnel.addr;
+        let mut lease = registry
+            .register(tunnel, &PublicKey::from([1; 32]), None)
+            .unwrap();
+
+        let pinned = CertificateDer::from_pem_slice(include_str!("../testdata/a1.crt").as_bytes())?;
+        let config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(Pinned(pinned)))
+                .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let shared = TcpListener::bind("127.0.0.1:0").await?;
+        let connect = |addr: SocketAddr| {
+            let connector = connector.clone();
+            tokio::spawn(async move {
+                let conn = TcpStream::connect(addr).await?;
+                let mut conn = connector.connect("a.example".try_into()?, conn).await?;
+                conn.write_all(b"hello").await?;
+                conn.flush().await?;
+                anyhow::Ok(conn)
+            })
+        };
+
+        // the tunnel's own port and the shared port both terminate TLS
+        for shared in [None, Some(&shared)] {
+            let client = match shared {
+                None => connect(own),
+                Some(shared) => {
+                    let client = connect(shared.local_addr()?);
+                    let (conn, addr) = shared.accept().await?;
+                    serve(conn, addr, registry.clone()).await?;
+                    client
+                }
+            };
+            let mut routed = match lease.next().await {
+                Event::Incoming((conn, _), _) => conn,
+                _ => panic!("not routed"),
+            };
+            let mut buf = [0u8; 5];
+            routed.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"hello");
+            drop(client.await??);
+        }
+        Ok(())
+    }
+}
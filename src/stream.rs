@@ -0,0 +1,137 @@
+use crate::protocol::CloseReason;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+// An external connection carried through a tunnel.
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {
+    // Close with a reset instead of a FIN once dropped.
+    fn set_reset(&self) -> io::Result<()>;
+
+    // What to answer instead of a reset when the connection fails before the
+    // backend sent anything, e.g. an HTTP error page.
+    fn error_page(&self, _reason: CloseReason) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl Conn for TcpStream {
+    fn set_reset(&self) -> io::Result<()> {
+        self.set_linger(Some(Duration::ZERO))
+    }
+}
+
+impl<C: Conn> Conn for tokio_rustls::server::TlsStream<C> {
+    fn set_reset(&self) -> io::Result<()> {
+        self.get_ref().0.set_reset()
+    }
+}
+
+impl<C: Conn + ?Sized> Conn for Box<C> {
+    fn set_reset(&self) -> io::Result<()> {
+        (**self).set_reset()
+    }
+
+    fn error_page(&self, reason: CloseReason) -> Option<Vec<u8>> {
+        (**self).error_page(reason)
+    }
+}
+
+// A connection whose first bytes were already read, e.g. to route it. They
+// are read again before the rest of the stream.
+pub struct Prefixed<C> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: C,
+    error_page: Option<fn(CloseReason) -> Vec<u8>>,
+}
+
+impl<C> Prefixed<C> {
+    pub fn new(prefix: Vec<u8>, inner: C) -> Prefixed<C> {
+        Prefixed {
+            prefix,
+            pos: 0,
+            inner,
+            error_page: None,
+        }
+    }
+
+    pub fn with_error_page(mut self, page: fn(CloseReason) -> Vec<u8>) -> Prefixed<C> {
+        self.error_page = Some(page);
+        self
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Prefixed<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = std::cmp::min(buf.remaining(), this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Prefixed<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<C: Conn> Conn for Prefixed<C> {
+    fn set_reset(&self) -> io::Result<()> {
+        self.inner.set_reset()
+    }
+
+    fn error_page(&self, reason: CloseReason) -> Option<Vec<u8>> {
+        self.error_page.map(|page| page(reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn prefixed() -> anyhow::Result<()> {
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut conn = Prefixed::new(b"GET / ".to_vec(), a);
+        b.write_all(b"HTTP/1.1").await?;
+        drop(b);
+
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"GET ");
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "/ HTTP/1.1");
+        Ok(())
+    }
+}
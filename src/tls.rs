@@ -0,0 +1,251 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+
+// Certificate and key files for a domain, written as DOMAIN=CERT,KEY. The
+// domain may be a wildcard like *.example.com covering a single label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertSpec {
+    pub domain: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl FromStr for CertSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<CertSpec> {
+        let (domain, files) = s
+            .split_once('=')
+            .ok_or(anyhow::anyhow!("expected DOMAIN=CERT,KEY, got {}", s))?;
+        let (cert, key) = files
+            .split_once(',')
+            .ok_or(anyhow::anyhow!("expected DOMAIN=CERT,KEY, got {}", s))?;
+        if domain.is_empty() || cert.is_empty() || key.is_empty() {
+            anyhow::bail!("expected DOMAIN=CERT,KEY, got {}", s);
+        }
+        Ok(CertSpec {
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            cert: cert.into(),
+            key: key.into(),
+        })
+    }
+}
+
+impl CertSpec {
+    fn covers(&self, domain: &str) -> bool {
+        match self.domain.strip_prefix("*.") {
+            Some(parent) => domain.split_once('.').is_some_and(|(label, rest)| {
+                !label.is_empty() && rest.eq_ignore_ascii_case(parent)
+            }),
+            None => self.domain.eq_ignore_ascii_case(domain),
+        }
+    }
+
+    fn modified(&self) -> std::io::Result<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path)?.modified();
+        Ok((modified(&self.cert)?, modified(&self.key)?))
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> anyhow::Result<Arc<CertifiedKey>> {
+        let chain = CertificateDer::pem_file_iter(&self.cert)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)?;
+        Ok(Arc::new(CertifiedKey::from_der(chain, key, provider)?))
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    modified: (SystemTime, SystemTime),
+    key: Arc<CertifiedKey>,
+}
+
+// The certificates TLS is terminated with, kept in sync with their files.
+#[derive(Debug)]
+pub struct Certs {
+    provider: Arc<CryptoProvider>,
+    entries: Vec<(CertSpec, Mutex<Loaded>)>,
+}
+
+impl Certs {
+    pub fn load(specs: Vec<CertSpec>) -> anyhow::Result<Arc<Certs>> {
+        let provider = Arc::new(ring::default_provider());
+        let mut entries = vec![];
+        for spec in specs {
+            let loaded = Loaded {
+                modified: spec.modified()?,
+                key: spec
+                    .load(&provider)
+                    .map_err(|e| anyhow::anyhow!("certificate for {}: {}", spec.domain, e))?,
+            };
+            entries.push((spec, Mutex::new(loaded)));
+        }
+        Ok(Arc::new(Certs { provider, entries }))
+    }
+
+    // The entry for domain, exact names win over wildcards.
+    fn find(&self, domain: &str) -> Option<usize> {
+        let exact = |spec: &CertSpec| !spec.domain.starts_with("*.") && spec.covers(domain);
+        self.entries
+            .iter()
+            .position(|(spec, _)| exact(spec))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .position(|(spec, _)| spec.covers(domain))
+            })
+    }
+
+    // Acceptor terminating TLS for a tunnel's domain, None if no certificate
+    // covers it.
+    pub fn acceptor(self: &Arc<Self>, domain: &str) -> Option<TlsAcceptor> {
+        let resolver = Resolver {
+            certs: self.clone(),
+            entry: self.find(domain)?,
+        };
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        Some(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    // Load the certificates whose files changed. A broken file keeps the
+    // previous certificate and is tried again on the next call, e.g. when
+    // only the certificate of a new pair was written so far.
+    pub fn reload(&self) {
+        for (spec, loaded) in &self.entries {
+            let modified = match spec.modified() {
+                Ok(modified) => modified,
+                Err(e) => {
+                    warn!("failed to check the certificate for {}, {}", spec.domain, e);
+                    continue;
+                }
+            };
+            if loaded.lock().unwrap().modified == modified {
+                continue;
+            }
+            match spec.load(&self.provider) {
+                Ok(key) => {
+                    info!("reloaded the certificate for {}", spec.domain);
+                    *loaded.lock().unwrap() = Loaded { modified, key };
+                }
+                Err(e) => warn!(
+                    "failed to reload the certificate for {}, {}",
+                    spec.domain, e
+                ),
+            }
+        }
+    }
+
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            // reads files, keep it off the runtime
+            let certs = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || certs.reload()).await {
+                warn!("failed to reload the certificates, {}", e);
+            }
+        }
+    }
+}
+
+// Serves the current certificate of one entry, whatever name the client asks
+// for, the listener belongs to a single domain.
+#[derive(Debug)]
+struct Resolver {
+    certs: Arc<Certs>,
+    entry: usize,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certs.entries[self.entry].1.lock().unwrap().key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT_1: &str = include_str!("../testdata/a1.crt");
+    const KEY_1: &str = include_str!("../testdata/a1.key");
+    const CERT_2: &str = include_str!("../testdata/a2.crt");
+    const KEY_2: &str = include_str!("../testdata/a2.key");
+
+    fn current(certs: &Certs, domain: &str) -> Vec<u8> {
+        let entry = certs.find(domain).unwrap();
+        certs.entries[entry].1.lock().unwrap().key.cert[0].to_vec()
+    }
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        let spec: CertSpec = "*.Example.com=/etc/tls/cert.pem,/etc/tls/key.pem".parse()?;
+        assert_eq!(
+            spec,
+            CertSpec {
+                domain: "*.example.com".to_string(),
+                cert: "/etc/tls/cert.pem".into(),
+                key: "/etc/tls/key.pem".into(),
+            }
+        );
+        assert!(spec.covers("a.example.com"));
+        assert!(!spec.covers("example.com"));
+        assert!(!spec.covers("a.b.example.com"));
+
+        assert!("example.com".parse::<CertSpec>().is_err());
+        assert!("example.com=cert.pem".parse::<CertSpec>().is_err());
+        assert!("=cert.pem,key.pem".parse::<CertSpec>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reload() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("revconn-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir)?;
+        let (cert, key) = (dir.join("a.crt"), dir.join("a.key"));
+        std::fs::write(&cert, CERT_1)?;
+        std::fs::write(&key, KEY_1)?;
+
+        let spec = |domain: &str| format!("{}={},{}", domain, cert.display(), key.display());
+        let certs = Certs::load(vec![spec("*.example").parse()?, spec("a.example").parse()?])?;
+        assert_eq!(certs.find("a.example"), Some(1));
+        assert_eq!(certs.find("b.example"), Some(0));
+        assert!(certs.acceptor("example").is_none());
+        assert!(certs.acceptor("a.example").is_some());
+        let first = current(&certs, "a.example");
+
+        // a certificate without its key is not picked up yet
+        let later = |path: &Path, secs| -> std::io::Result<()> {
+            let file = std::fs::File::options().write(true).open(path)?;
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs))
+        };
+        std::fs::write(&cert, CERT_2)?;
+        later(&cert, 10)?;
+        certs.reload();
+        assert_eq!(current(&certs, "a.example"), first);
+
+        std::fs::write(&key, KEY_2)?;
+        later(&key, 10)?;
+        certs.reload();
+        assert_ne!(current(&certs, "a.example"), first);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
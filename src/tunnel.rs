@@ -0,0 +1,1104 @@
+use crate::{
+    callback::OnShutdown,
+    ports::PortLease,
+    protocol::{CloseReason, Role},
+    stream::Conn,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, net::TcpListener, sync::mpsc, task::AbortHandle};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use x25519_dalek::PublicKey;
+
+// An accepted external connection and its peer address.
+pub type Incoming = (Box<dyn Conn>, SocketAddr);
+
+// Longest a client may take for its TLS handshake on an exposed listener.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where a tunnel accepts external connections. With an acceptor TLS is
+// terminated before connections are dispatched.
+pub struct Listener {
+    pub tcp: TcpListener,
+    pub tls: Option<TlsAcceptor>,
+}
+
+impl From<TcpListener> for Listener {
+    fn from(tcp: TcpListener) -> Listener {
+        Listener { tcp, tls: None }
+    }
+}
+
+// How connections are spread over the sessions pooled on a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Balance {
+    RoundRobin,
+    // the session with the fewest open connections
+    LeastConnections,
+    // the same session for the same peer address while the pool is unchanged
+    SourceIp,
+}
+
+// How a tunnel hands out external connections, and how many it holds, for
+// how long, while no session is attached.
+#[derive(Clone, Copy, Debug)]
+pub struct DispatchPolicy {
+    pub balance: Balance,
+    pub queue_size: usize,
+    pub queue_timeout: Duration,
+}
+
+// Counts a connection against its session until dropped.
+pub struct Active(Arc<AtomicUsize>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Attached {
+    id: u64,
+    tx: mpsc::UnboundedSender<(Incoming, Active)>,
+    active: Arc<AtomicUsize>,
+}
+
+struct Dispatch {
+    sessions: Vec<Attached>,
+    next: usize,
+    queue: VecDeque<(Instant, Incoming)>,
+}
+
+impl Dispatch {
+    fn purge(&mut self, timeout: Duration) {
+        while let Some((at, (_, addr))) = self.queue.front() {
+            if at.elapsed() < timeout {
+                break;
+            }
+            debug!("drop queued connection from {}, no session in time", addr);
+            if let Some((_, conn)) = self.queue.pop_front() {
+                refuse(conn, CloseReason::Timeout);
+            }
+        }
+    }
+
+    fn pick(&mut self, balance: Balance, peer: &SocketAddr) -> usize {
+        let n = self.sessions.len();
+        match balance {
+            Balance::RoundRobin => {
+                let i = self.next % n;
+                self.next = i + 1;
+                i
+            }
+            Balance::LeastConnections => {
+                // start after the last pick so ties are taken in turn
+                let start = self.next % n;
+                let i = (start..n)
+                    .chain(0..start)
+                    .min_by_key(|i| self.sessions[*i].active.load(Ordering::Relaxed))
+                    .unwrap();
+                self.next = i + 1;
+                i
+            }
+            // rendezvous hashing, a peer only moves when its session leaves
+            Balance::SourceIp => (0..n)
+                .max_by_key(|i| {
+                    let mut h = DefaultHasher::new();
+                    (peer.ip(), self.sessions[*i].id).hash(&mut h);
+                    h.finish()
+                })
+                .unwrap(),
+        }
+    }
+}
+
+// Give up on a connection no session took, with an error page if it has one.
+fn refuse((mut conn, _): Incoming, reason: CloseReason) {
+    if let Some(page) = conn.error_page(reason) {
+        tokio::spawn(async move {
+            let _ = conn.write_all(&page).await;
+            let _ = conn.shutdown().await;
+        });
+    }
+}
+
+// Hand the connection to one of the attached sessions, or queue it until
+// one attaches.
+fn dispatch(state: &Arc<Mutex<Dispatch>>, policy: DispatchPolicy, mut conn: Incoming) {
+    let mut d = state.lock().unwrap();
+    while !d.sessions.is_empty() {
+        let i = d.pick(policy.balance, &conn.1);
+        let session = &d.sessions[i];
+        session.active.fetch_add(1, Ordering::Relaxed);
+        let active = Active(session.active.clone());
+        match session.tx.send((conn, active)) {
+            Ok(()) => return,
+            Err(e) => {
+                // the session is going away, try another one
+                conn = (e.0).0;
+                d.sessions.remove(i);
+            }
+        }
+    }
+
+    d.purge(policy.queue_timeout);
+    if d.queue.len() >= policy.queue_size {
+        warn!("connection queue is full, drop connection from {}", conn.1);
+        refuse(conn, CloseReason::ConnectRefused);
+        return;
+    }
+    debug!("queue connection from {} until a session attaches", conn.1);
+    d.queue.push_back((Instant::now(), conn));
+
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        tokio::time::sleep(policy.queue_timeout).await;
+        if let Some(state) = Weak::upgrade(&state) {
+            state.lock().unwrap().purge(policy.queue_timeout);
+        }
+    });
+}
+
+// Terminate TLS if the tunnel does, then hand the connection out. The
+// handshake runs aside so a slow client does not hold up the others.
+fn accept(
+    state: &Arc<Mutex<Dispatch>>,
+    policy: DispatchPolicy,
+    tls: Option<&TlsAcceptor>,
+    (conn, peer): Incoming,
+) {
+    let tls = match tls {
+        Some(tls) => tls.clone(),
+        None => return dispatch(state, policy, (conn, peer)),
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(conn)).await {
+            Ok(Ok(conn)) => dispatch(&state, policy, (Box::new(conn), peer)),
+            Ok(Err(e)) => debug!("TLS handshake with {} failed, {}", peer, e),
+            Err(_) => debug!("TLS handshake with {} timed out", peer),
+        }
+    });
+}
+
+// Owned by the accept task. The listener is closed before the port goes back
+// to the range, or the next tunnel on it could fail to bind.
+struct Bound {
+    listener: TcpListener,
+    _port: Option<PortLease>,
+}
+
+// The exposed side of a client's tunnel. It outlives a single session so that
+// a reconnecting client gets the same port back, connections arriving in
+// between are queued.
+pub struct Tunnel {
+    pub conn_uid: String,
+    pub domain: String,
+    pub path: String,
+    pub addr: SocketAddr,
+    policy: DispatchPolicy,
+    tls: Option<TlsAcceptor>,
+    dispatch: Arc<Mutex<Dispatch>>,
+    accept: AbortHandle,
+    // reports the shutdown to the callback once the tunnel is released, set
+    // only once it is registered
+    on_shutdown: OnceLock<OnShutdown>,
+}
+
+impl Tunnel {
+    pub fn new(
+        conn_uid: String,
+        domain: String,
+        path: String,
+        listener: impl Into<Listener>,
+        port: Option<PortLease>,
+        policy: DispatchPolicy,
+    ) -> std::io::Result<Tunnel> {
+        let Listener { tcp: listener, tls } = listener.into();
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(Dispatch {
+            sessions: vec![],
+            next: 0,
+            queue: VecDeque::new(),
+        }));
+
+        let accept = {
+            let (state, tls) = (state.clone(), tls.clone());
+            let bound = Bound {
+                listener,
+                _port: port,
+            };
+            tokio::spawn(async move {
+                // move all of it in, not just the listener it uses
+                let bound = bound;
+                loop {
+                    match bound.listener.accept().await {
+                        Ok((conn, peer)) => {
+                            accept(&state, policy, tls.as_ref(), (Box::new(conn), peer))
+                        }
+                        Err(e) => {
+                            // most likely out of file descriptors, give it a moment
+                            warn!("failed to accept on {}, {}", addr, e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            })
+            .abort_handle()
+        };
+
+        Ok(Tunnel {
+            conn_uid,
+            domain,
+            path,
+            addr,
+            policy,
+            tls,
+            dispatch: state,
+            accept,
+            on_shutdown: OnceLock::new(),
+        })
+    }
+
+    // The URL the tunnel is reachable at, {domain}, {path} and {port} of the
+    // template are filled in.
+    pub fn public_url(&self, template: &str) -> String {
+        template
+            .replace("{domain}", &self.domain)
+            .replace("{path}", &self.path)
+            .replace("{port}", &self.addr.port().to_string())
+    }
+
+    // A connection from a shared listener that passes TLS through, it is
+    // terminated here the same as on the tunnel's own port.
+    pub fn accept(&self, conn: Incoming) {
+        accept(&self.dispatch, self.policy, self.tls.as_ref(), conn);
+    }
+
+    // A connection that is already in the clear, or meant to stay encrypted.
+    pub fn dispatch(&self, conn: Incoming) {
+        dispatch(&self.dispatch, self.policy, conn);
+    }
+
+    pub fn on_shutdown(&self, on_shutdown: OnShutdown) {
+        let _ = self.on_shutdown.set(on_shutdown);
+    }
+
+    // Route connections to a new session, starting with the queued ones.
+    fn attach(&self, session: u64, tx: mpsc::UnboundedSender<(Incoming, Active)>) {
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut d = self.dispatch.lock().unwrap();
+        d.purge(self.policy.queue_timeout);
+        for (_, conn) in d.queue.drain(..) {
+            active.fetch_add(1, Ordering::Relaxed);
+            let _ = tx.send((conn, Active(active.clone())));
+        }
+        d.sessions.push(Attached {
+            id: session,
+            tx,
+            active,
+        });
+    }
+
+    fn detach(&self, session: u64) {
+        self.dispatch
+            .lock()
+            .unwrap()
+            .sessions
+            .retain(|s| s.id != session);
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+// What to do when a domain/path is claimed while other sessions serve it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Conflict {
+    // turn the newcomer away
+    Reject,
+    // shut the old sessions down and hand the tunnel to the newcomer
+    Replace,
+    // attach the newcomer next to the others
+    Pool,
+}
+
+struct Session {
+    id: u64,
+    // the authenticated client, a tunnel id is only honoured for the same key
+    owner: PublicKey,
+    tunnel_id: Option<String>,
+    notify: mpsc::UnboundedSender<Notice>,
+    // a standby holds on to its channel until it is promoted
+    standby: Option<mpsc::UnboundedSender<(Incoming, Active)>>,
+}
+
+impl Session {
+    fn kick(self, tunnel: &Tunnel, message: String) {
+        tunnel.detach(self.id);
+        let _ = self.notify.send(Notice::Kicked(message));
+    }
+}
+
+enum Notice {
+    Kicked(String),
+    Promoted,
+}
+
+struct Entry {
+    tunnel: Arc<Tunnel>,
+    sessions: Vec<Session>,
+    // bumped on every park so a stale expiry leaves a resumed tunnel alone
+    generation: u64,
+}
+
+impl Entry {
+    fn has_active(&self) -> bool {
+        self.sessions.iter().any(|s| s.standby.is_none())
+    }
+
+    // Hand the traffic to the oldest standby once no active session is left.
+    fn promote(&mut self) {
+        if self.has_active() {
+            return;
+        }
+        if let Some(session) = self.sessions.iter_mut().find(|s| s.standby.is_some()) {
+            info!("promote standby session={}", session.id);
+            self.tunnel
+                .attach(session.id, session.standby.take().unwrap());
+            let _ = session.notify.send(Notice::Promoted);
+        }
+    }
+}
+
+// Domain and path kept apart, joined they could name another tunnel.
+type Key = (String, String);
+
+#[derive(Default)]
+struct State {
+    tunnels: HashMap<Key, Entry>,
+    next_session: u64,
+}
+
+fn key(domain: &str, path: &str) -> Key {
+    (domain.to_string(), path.to_string())
+}
+
+// Whether a request for path falls under a tunnel claiming prefix, which
+// only matches whole path segments.
+fn path_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?']),
+        None => false,
+    }
+}
+
+// Tunnels by domain and path, kept for a grace period after their last
+// session ended.
+pub struct Registry {
+    grace: Duration,
+    conflict: Conflict,
+    state: Mutex<State>,
+}
+
+pub enum Claim {
+    Attached(Lease),
+    Vacant,
+    Busy(String),
+}
+
+impl Registry {
+    pub fn new(grace: Duration, conflict: Conflict) -> Arc<Registry> {
+        Arc::new(Registry {
+            grace,
+            conflict,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    // Attach to the tunnel serving domain and path. A session with the same
+    // tunnel id and key is the same client reconnecting and is taken over, it
+    // most likely has not noticed the disconnect yet. A standby never
+    // conflicts, it is only active while no other session is.
+    pub fn claim(
+        self: &Arc<Self>,
+        domain: &str,
+        path: &str,
+        port: Option<u16>,
+        owner: &PublicKey,
+        tunnel_id: Option<&str>,
+        standby: bool,
+    ) -> Claim {
+        let key = key(domain, path);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_session;
+        let entry = match state.tunnels.get_mut(&key) {
+            Some(entry) => entry,
+            None => return Claim::Vacant,
+        };
+
+        if port.is_some_and(|p| p != entry.tunnel.addr.port()) {
+            if entry.sessions.is_empty() {
+                // nobody is using the old port, start over on the new one
+                state.tunnels.remove(&key);
+                return Claim::Vacant;
+            }
+            return Claim::Busy(format!(
+                "domain={}, path={} is already exposed on port {}",
+                domain,
+                path,
+                entry.tunnel.addr.port()
+            ));
+        }
+
+        if let Some(tunnel_id) = tunnel_id {
+            let previous = entry
+                .sessions
+                .iter()
+                .position(|s| s.tunnel_id.as_deref() == Some(tunnel_id));
+            if let Some(i) = previous {
+                // the id alone proves nothing, any client may send it
+                if entry.sessions[i].owner != *owner {
+                    return Claim::Busy(format!(
+                        "domain={}, path={} is already claimed by another client",
+                        domain, path
+                    ));
+                }
+                // it takes its own place back, a standby is not promoted for it
+                let previous = entry.sessions.remove(i);
+                previous.kick(&entry.tunnel, "replaced by a new session".to_string());
+            }
+        }
+
+        let role = if standby && entry.has_active() {
+            Role::Standby
+        } else {
+            Role::Active
+        };
+        if role == Role::Active && entry.has_active() {
+            match self.conflict {
+                Conflict::Reject => {
+                    return Claim::Busy(format!(
+                        "domain={}, path={} is already claimed by another session",
+                        domain, path
+                    ));
+                }
+                Conflict::Replace => {
+                    info!(
+                        "replace sessions of tunnel domain={}, path={}",
+                        domain, path
+                    );
+                    let (previous, standbys) = std::mem::take(&mut entry.sessions)
+                        .into_iter()
+                        .partition(|s| s.standby.is_none());
+                    entry.sessions = standbys;
+                    for previous in previous {
+                        previous.kick(
+                            &entry.tunnel,
+                            format!(
+                                "replaced by a new session for domain={}, path={}",
+                                domain, path
+                            ),
+                        );
+                    }
+                }
+                Conflict::Pool => {
+                    info!(
+                        "pool session with {} others for tunnel domain={}, path={}",
+                        entry.sessions.len(),
+                        domain,
+                        path
+                    );
+                }
+            }
+        }
+
+        state.next_session += 1;
+        let entry = state.tunnels.get_mut(&key).unwrap();
+        let (session, lease) = self.lease(key, id, entry.tunnel.clone(), owner, tunnel_id, role);
+        entry.sessions.push(session);
+        Claim::Attached(lease)
+    }
+
+    // Reserve a new tunnel for its domain and path. None if another session
+    // registered one in the meantime, claim again then.
+    pub fn register(
+        self: &Arc<Self>,
+        tunnel: Tunnel,
+        owner: &PublicKey,
+        tunnel_id: Option<&str>,
+    ) -> Option<Lease> {
+        let key = key(&tunnel.domain, &tunnel.path);
+        let mut state = self.state.lock().unwrap();
+        if state.tunnels.contains_key(&key) {
+            return None;
+        }
+        let id = state.next_session;
+        state.next_session += 1;
+        let tunnel = Arc::new(tunnel);
+        let (session, lease) = self.lease(
+            key.clone(),
+            id,
+            tunnel.clone(),
+            owner,
+            tunnel_id,
+            Role::Active,
+        );
+        state.tunnels.insert(
+            key,
+            Entry {
+                tunnel,
+                sessions: vec![session],
+                generation: 0,
+            },
+        );
+        Some(lease)
+    }
+
+    // The tunnel for a request to host and path, the one with the longest
+    // matching path wins.
+    pub fn lookup(&self, host: &str, path: &str) -> Option<Arc<Tunnel>> {
+        let state = self.state.lock().unwrap();
+        state
+            .tunnels
+            .values()
+            .filter(|e| e.tunnel.domain.eq_ignore_ascii_case(host))
+            .filter(|e| path_matches(&e.tunnel.path, path))
+            .max_by_key(|e| e.tunnel.path.len())
+            .map(|e| e.tunnel.clone())
+    }
+
+    fn lease(
+        self: &Arc<Self>,
+        key: Key,
+        id: u64,
+        tunnel: Arc<Tunnel>,
+        owner: &PublicKey,
+        tunnel_id: Option<&str>,
+        role: Role,
+    ) -> (Session, Lease) {
+        let (notify, notices) = mpsc::unbounded_channel();
+        let (tx, incoming) = mpsc::unbounded_channel();
+        let standby = match role {
+            Role::Active => {
+                tunnel.attach(id, tx);
+                None
+            }
+            Role::Standby => Some(tx),
+        };
+        let session = Session {
+            id,
+            owner: *owner,
+            tunnel_id: tunnel_id.map(str::to_string),
+            notify,
+            standby,
+        };
+        let lease = Lease {
+            tunnel,
+            role,
+            incoming,
+            notices,
+            registry: self.clone(),
+            key,
+            id,
+        };
+        (session, lease)
+    }
+
+    fn detach(self: &Arc<Self>, key: &Key, session: u64) {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.tunnels.get_mut(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        // already kicked by a newer session
+        let i = match entry.sessions.iter().position(|s| s.id == session) {
+            Some(i) => i,
+            None => return,
+        };
+        entry.sessions.remove(i);
+        entry.tunnel.detach(session);
+        if !entry.sessions.is_empty() {
+            entry.promote();
+            return;
+        }
+
+        entry.generation += 1;
+        if self.grace.is_zero() {
+            state.tunnels.remove(key);
+            return;
+        }
+        info!(
+            "keep tunnel domain={}, path={}, port={} for {:?}",
+            key.0,
+            key.1,
+            entry.tunnel.addr.port(),
+            self.grace
+        );
+        let generation = entry.generation;
+        let registry = self.clone();
+        let key = key.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(registry.grace).await;
+            registry.expire(&key, generation);
+        });
+    }
+
+    fn expire(&self, key: &Key, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        let expired = state
+            .tunnels
+            .get(key)
+            .is_some_and(|e| e.sessions.is_empty() && e.generation == generation);
+        if expired {
+            info!("release tunnel domain={}, path={}", key.0, key.1);
+            state.tunnels.remove(key);
+        }
+    }
+}
+
+// A session's hold on a tunnel, parks the tunnel in the registry when dropped.
+pub struct Lease {
+    pub tunnel: Arc<Tunnel>,
+    pub role: Role,
+    incoming: mpsc::UnboundedReceiver<(Incoming, Active)>,
+    notices: mpsc::UnboundedReceiver<Notice>,
+    registry: Arc<Registry>,
+    key: Key,
+    id: u64,
+}
+
+impl Lease {
+    // The next external connection for this session, or a change of its
+    // standing: promoted from standby, or kicked with the message to shut the
+    // session down with once another session took the tunnel over.
+    pub async fn next(&mut self) -> Event {
+        tokio::select! {
+            // a promotion is reported before the first connection it brings
+            biased;
+            Some(notice) = self.notices.recv() => match notice {
+                Notice::Kicked(message) => Event::Kicked(message),
+                Notice::Promoted => {
+                    self.role = Role::Active;
+                    Event::Promoted
+                }
+            },
+            Some((conn, active)) = self.incoming.recv() => Event::Incoming(conn, active),
+            // gone from the registry, the session was told why
+            else => std::future::pending().await,
+        }
+    }
+}
+
+pub enum Event {
+    // the session keeps Active for as long as the connection is open
+    Incoming(Incoming, Active),
+    Kicked(String),
+    Promoted,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.registry.detach(&self.key, self.id);
+        // connections this session never picked up go to the next one
+        self.incoming.close();
+        while let Ok((conn, _)) = self.incoming.try_recv() {
+            self.tunnel.dispatch(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    async fn tunnel() -> anyhow::Result<Tunnel> {
+        balanced(Balance::RoundRobin).await
+    }
+
+    async fn balanced(balance: Balance) -> anyhow::Result<Tunnel> {
+        at("/", balance).await
+    }
+
+    async fn at(path: &str, balance: Balance) -> anyhow::Result<Tunnel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        Ok(Tunnel::new(
+            "conn-test".to_string(),
+            "a.example".to_string(),
+            path.to_string(),
+            listener,
+            None,
+            DispatchPolicy {
+                balance,
+                queue_size: 2,
+                queue_timeout: Duration::from_millis(200),
+            },
+        )?)
+    }
+
+    fn attached(claim: Claim) -> Lease {
+        match claim {
+            Claim::Attached(lease) => lease,
+            Claim::Vacant => panic!("vacant"),
+            Claim::Busy(reason) => panic!("busy, {}", reason),
+        }
+    }
+
+    async fn next_conn(lease: &mut Lease) -> SocketAddr {
+        match lease.next().await {
+            Event::Incoming((_, addr), _) => addr,
+            Event::Kicked(message) => panic!("kicked, {}", message),
+            Event::Promoted => panic!("promoted"),
+        }
+    }
+
+    fn client(n: u8) -> PublicKey {
+        PublicKey::from([n; 32])
+    }
+
+    #[tokio::test]
+    async fn resume() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let lease = registry
+            .register(tunnel().await?, &client(1), None)
+            .unwrap();
+        let port = lease.tunnel.addr.port();
+        drop(lease);
+
+        let lease = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+        assert_eq!(lease.tunnel.addr.port(), port);
+        drop(lease);
+        assert!(matches!(
+            registry.claim("a.example", "/b", None, &client(1), None, false),
+            Claim::Vacant
+        ));
+
+        // a parked tunnel on another port makes way
+        assert!(matches!(
+            registry.claim(
+                "a.example",
+                "/",
+                Some(port.wrapping_add(1)),
+                &client(1),
+                None,
+                false
+            ),
+            Claim::Vacant
+        ));
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(1), None, false),
+            Claim::Vacant
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expire() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_millis(20), Conflict::Reject);
+        drop(registry.register(tunnel().await?, &client(1), None));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(1), None, false),
+            Claim::Vacant
+        ));
+
+        let registry = Registry::new(Duration::ZERO, Conflict::Reject);
+        drop(registry.register(tunnel().await?, &client(1), None));
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(1), None, false),
+            Claim::Vacant
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let mut old = registry
+            .register(tunnel().await?, &client(1), Some("a"))
+            .unwrap();
+        assert!(registry
+            .register(tunnel().await?, &client(1), None)
+            .is_none());
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(1), Some("b"), false),
+            Claim::Busy(_)
+        ));
+        // the id alone does not let another client take over
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(2), Some("a"), false),
+            Claim::Busy(_)
+        ));
+
+        // the same client reconnecting takes its tunnel over
+        let new = attached(registry.claim("a.example", "/", None, &client(1), Some("a"), false));
+        match old.next().await {
+            Event::Kicked(message) => assert_eq!(message, "replaced by a new session"),
+            _ => panic!("not kicked"),
+        }
+
+        // the old session going away must not park the tunnel
+        drop(old);
+        assert!(matches!(
+            registry.claim("a.example", "/", None, &client(1), None, false),
+            Claim::Busy(_)
+        ));
+        drop(new);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Replace);
+        let mut old = registry
+            .register(tunnel().await?, &client(1), None)
+            .unwrap();
+        let port = old.tunnel.addr.port();
+        let mut new = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+        assert!(matches!(old.next().await, Event::Kicked(_)));
+
+        let conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut new).await, conn.local_addr()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Pool);
+        let mut a = registry
+            .register(tunnel().await?, &client(1), None)
+            .unwrap();
+        let port = a.tunnel.addr.port();
+        let mut b = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+
+        let c1 = TcpStream::connect(("127.0.0.1", port)).await?;
+        let c2 = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut a).await, c1.local_addr()?);
+        assert_eq!(next_conn(&mut b).await, c2.local_addr()?);
+
+        // the rest goes to the session still there
+        drop(a);
+        let c3 = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut b).await, c3.local_addr()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let lease = registry
+            .register(tunnel().await?, &client(1), None)
+            .unwrap();
+        let port = lease.tunnel.addr.port();
+        drop(lease);
+
+        // held while the client is away, the one over the limit is closed
+        let mut conns = vec![];
+        for _ in 0..3 {
+            conns.push(TcpStream::connect(("127.0.0.1", port)).await?);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut buf = [0u8; 1];
+        assert_eq!(conns[2].read(&mut buf).await?, 0);
+
+        let mut lease = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+        for conn in &conns[..2] {
+            assert_eq!(next_conn(&mut lease).await, conn.local_addr()?);
+        }
+        drop(lease);
+
+        // given up on once the timeout passed
+        let mut conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(conn.read(&mut buf).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn least_connections() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Pool);
+        let tunnel = balanced(Balance::LeastConnections).await?;
+        let port = tunnel.addr.port();
+        let mut a = registry.register(tunnel, &client(1), None).unwrap();
+        let mut b = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+
+        let _c1 = TcpStream::connect(("127.0.0.1", port)).await?;
+        let busy = match a.next().await {
+            Event::Incoming(_, active) => active,
+            _ => panic!("no connection"),
+        };
+        let _c2 = TcpStream::connect(("127.0.0.1", port)).await?;
+        let done = match b.next().await {
+            Event::Incoming(_, active) => active,
+            _ => panic!("no connection"),
+        };
+        drop(done);
+
+        // b is idle again while a still holds a connection
+        let c3 = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut b).await, c3.local_addr()?);
+        drop(busy);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn source_ip() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Pool);
+        let tunnel = balanced(Balance::SourceIp).await?;
+        let port = tunnel.addr.port();
+        let mut a = registry.register(tunnel, &client(1), None).unwrap();
+        let mut b = attached(registry.claim("a.example", "/", None, &client(1), None, false));
+
+        // every connection comes from 127.0.0.1 and sticks to one session
+        let mut conns = vec![];
+        for _ in 0..3 {
+            conns.push(TcpStream::connect(("127.0.0.1", port)).await?);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (mut sticky, mut idle) = tokio::select! {
+            _ = a.next() => (a, b),
+            _ = b.next() => (b, a),
+        };
+        next_conn(&mut sticky).await;
+        next_conn(&mut sticky).await;
+
+        // the peer moves once its session leaves
+        drop(sticky);
+        let conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut idle).await, conn.local_addr()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn standby() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let active = registry
+            .register(tunnel().await?, &client(1), None)
+            .unwrap();
+        let port = active.tunnel.addr.port();
+        let mut standby = attached(registry.claim("a.example", "/", None, &client(1), None, true));
+        assert_eq!(standby.role, Role::Standby);
+
+        // no traffic until the active session is gone
+        let conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(active);
+        assert!(matches!(standby.next().await, Event::Promoted));
+        assert_eq!(standby.role, Role::Active);
+        assert_eq!(next_conn(&mut standby).await, conn.local_addr()?);
+
+        // a standby with nobody to stand by for is active right away
+        drop(standby);
+        let lease = attached(registry.claim("a.example", "/", None, &client(1), None, true));
+        assert_eq!(lease.role, Role::Active);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takeover_with_standby() -> anyhow::Result<()> {
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let mut old = registry
+            .register(tunnel().await?, &client(1), Some("a"))
+            .unwrap();
+        let port = old.tunnel.addr.port();
+        let mut standby =
+            attached(registry.claim("a.example", "/", None, &client(2), Some("b"), true));
+
+        // the primary reconnecting gets its place back
+        let mut new =
+            attached(registry.claim("a.example", "/", None, &client(1), Some("a"), false));
+        assert_eq!(new.role, Role::Active);
+        assert!(matches!(old.next().await, Event::Kicked(_)));
+        drop(old);
+
+        let conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        assert_eq!(next_conn(&mut new).await, conn.local_addr()?);
+        tokio::select! {
+            _ = standby.next() => panic!("standby disturbed"),
+            _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+        }
+        assert_eq!(standby.role, Role::Standby);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup() -> anyhow::Result<()> {
+        assert!(path_matches("/", "/anything"));
+        assert!(path_matches("/api", "/api"));
+        assert!(path_matches("/api", "/api/users?id=1"));
+        assert!(path_matches("/api", "/api?id=1"));
+        assert!(!path_matches("/api", "/apis"));
+        assert!(!path_matches("/api/", "/api"));
+
+        let registry = Registry::new(Duration::from_secs(60), Conflict::Reject);
+        let _root = registry.register(at("/", Balance::RoundRobin).await?, &client(1), None);
+        let _api = registry.register(at("/api", Balance::RoundRobin).await?, &client(1), None);
+        let path = |host: &str, path: &str| registry.lookup(host, path).map(|t| t.path.clone());
+        assert_eq!(path("a.example", "/api/users"), Some("/api".to_string()));
+        assert_eq!(path("A.Example", "/apis"), Some("/".to_string()));
+        assert_eq!(path("b.example", "/"), None);
+
+        // domain and path are not run together
+        assert!(matches!(
+            registry.claim("a.exampl", "e/", None, &client(2), None, false),
+            Claim::Vacant
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_url() -> anyhow::Result<()> {
+        let tunnel = at("/api", Balance::RoundRobin).await?;
+        let port = tunnel.addr.port();
+        assert_eq!(
+            tunnel.public_url("https://{domain}{path}"),
+            "https://a.example/api"
+        );
+        assert_eq!(
+            tunnel.public_url("http://{domain}:{port}/"),
+            format!("http://a.example:{}/", port)
+        );
+        assert_eq!(tunnel.public_url("static"), "static");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn port_released_after_listener() -> anyhow::Result<()> {
+        let free = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let ports = crate::ports::Ports::new(free.to_string().parse()?);
+        let (listener, lease) = ports.bind("127.0.0.1".parse()?)?;
+        let port = lease.port;
+        let tunnel = Tunnel::new(
+            "conn-test".to_string(),
+            "a.example".to_string(),
+            "/".to_string(),
+            listener,
+            Some(lease),
+            DispatchPolicy {
+                balance: Balance::RoundRobin,
+                queue_size: 2,
+                queue_timeout: Duration::from_millis(200),
+            },
+        )?;
+        drop(tunnel);
+
+        // the port is handed out again only once it can be bound
+        let lease = ports.reserve_within(port, Duration::from_secs(1)).await;
+        assert_eq!(lease.map(|l| l.port), Some(port));
+        crate::ports::bind(SocketAddr::new("127.0.0.1".parse()?, port))?;
+        Ok(())
+    }
+}
@@ -1,38 +1,179 @@
 use base64::{engine::general_purpose, Engine as _};
+use std::{collections::HashMap, future::Future, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    sync::mpsc::{error::TrySendError, Receiver, Sender},
 };
-use tracing::debug;
+use tracing::{debug, warn};
+use x25519_dalek::{PublicKey, StaticSecret};
 
-use crate::protocol::Message;
+use crate::{
+    protocol::{Capabilities, CloseReason, Message},
+    stream::Conn,
+};
+
+// Live connections of one session. The server hands out ids in increasing
+// order, which tells frames for connections that were already closed apart
+// from frames for ids that were never opened.
+#[derive(Default)]
+pub struct ConnectionTable {
+    conns: HashMap<u32, Connection>,
+    highest_id: u32,
+    stray_frames: u64,
+}
+
+struct Connection {
+    tx: Sender<Message>,
+    // bytes the peer may still send before it needs a WindowUpdate, None
+    // without flow control
+    window: Option<usize>,
+}
+
+pub enum Route {
+    Queued,
+    Closed(Message),
+    Unknown(Message),
+    // the peer sent more than it was granted, the connection was dropped
+    // and its task resets it
+    Overrun,
+}
+
+// Bytes a peer may have in flight on one connection before it has to wait
+// for a WindowUpdate.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+// Frames queued for a connection without flow control. A peer that outruns
+// the connection is reset instead of being buffered without limit.
+const QUEUE_WITHOUT_FLOW_CONTROL: usize = 64;
+
+impl ConnectionTable {
+    // Register a connection and hand out the receiving end of its frames.
+    pub fn open(&mut self, id: u32, capabilities: Capabilities) -> Receiver<Message> {
+        // with flow control the window bounds the Data frames, each carries a
+        // byte at least
+        let (window, queue) = if capabilities.contains(Capabilities::FLOW_CONTROL) {
+            (Some(INITIAL_WINDOW as usize), INITIAL_WINDOW as usize)
+        } else {
+            (None, QUEUE_WITHOUT_FLOW_CONTROL)
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(queue);
+        self.highest_id = std::cmp::max(self.highest_id, id);
+        self.conns.insert(id, Connection { tx, window });
+        rx
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Sender<Message>> {
+        self.conns.remove(&id).map(|c| c.tx)
+    }
+
+    // Queue a frame from the peer for connection `id`. Frames that have
+    // nowhere to go are counted as stray.
+    pub fn route(&mut self, id: u32, message: Message) -> Route {
+        let conn = match self.conns.get_mut(&id) {
+            Some(conn) => conn,
+            None => {
+                self.stray_frames += 1;
+                return if id != 0 && id <= self.highest_id {
+                    Route::Closed(message)
+                } else {
+                    Route::Unknown(message)
+                };
+            }
+        };
+        if let (Message::Data { data, .. }, Some(window)) = (&message, &mut conn.window) {
+            // an empty frame takes a byte as well, it is not free to flood
+            let bytes = std::cmp::max(data.len(), 1);
+            if bytes > *window {
+                self.conns.remove(&id);
+                return Route::Overrun;
+            }
+            *window -= bytes;
+        }
+        match conn.tx.try_send(message) {
+            Ok(()) => Route::Queued,
+            // the task is done and about to report its close
+            Err(TrySendError::Closed(message)) => Route::Closed(message),
+            Err(TrySendError::Full(_)) => {
+                self.conns.remove(&id);
+                Route::Overrun
+            }
+        }
+    }
+
+    // Account a WindowUpdate sent to the peer.
+    pub fn grant(&mut self, id: u32, bytes: u32) {
+        if let Some(window) = self.conns.get_mut(&id).and_then(|c| c.window.as_mut()) {
+            *window = window.saturating_add(bytes as usize);
+        }
+    }
+
+    pub fn stray_frames(&self) -> u64 {
+        self.stray_frames
+    }
+}
 
-async fn handle_connection_inner(
+async fn handle_connection_inner<C: Conn>(
     conn_id: u32,
-    mut rx: tokio::sync::mpsc::Receiver<Message>,
-    s2c_tx: tokio::sync::mpsc::Sender<Message>,
-    mut conn: TcpStream,
-) -> anyhow::Result<()> {
-    let (mut ri, mut wi) = conn.split();
+    mut rx: Receiver<Message>,
+    s2c_tx: Sender<Message>,
+    conn: &mut C,
+    capabilities: Capabilities,
+) -> anyhow::Result<CloseReason> {
+    let flow_control = capabilities.contains(Capabilities::FLOW_CONTROL);
+    let half_close = capabilities.contains(Capabilities::HALF_CLOSE);
+    let (mut ri, mut wi) = tokio::io::split(&mut *conn);
     let mut buf = vec![0u8; 8192];
+    // bytes we may still send to the peer for this connection
+    let mut credit = if flow_control {
+        INITIAL_WINDOW as usize
+    } else {
+        usize::MAX
+    };
+    let mut read_open = true;
+    let mut write_open = true;
+    let mut written = false;
 
     // let mut frame_reader =
     //     tokio_util::codec::FramedRead::new(ri, tokio_util::codec::LengthDelimitedCodec::new());
 
-    loop {
+    let reason = loop {
+        let limit = if read_open {
+            std::cmp::min(buf.len(), credit)
+        } else {
+            0
+        };
         tokio::select! {
             message = rx.recv() => {
-                let message = message.ok_or(anyhow::anyhow!("no message"))?;
+                let message = match message {
+                    Some(message) => message,
+                    // dropped by the session, it overran its window or the
+                    // session is gone
+                    None => break CloseReason::IoError,
+                };
                 debug!("message from receiver {:?}", message);
                 match message {
                     Message::Data{id: _, data} => {
                         wi.write_all(&data).await?;
+                        written = true;
+                        if flow_control {
+                            s2c_tx.send(Message::WindowUpdate { id: conn_id, bytes: data.len() as u32 }).await?;
+                        }
+                    }
+                    Message::WindowUpdate{id: _, bytes} => {
+                        credit = credit.saturating_add(bytes as usize);
                     }
-                    Message::CloseConnection{id: _} => {
-                        break;
+                    Message::ShutdownWrite{id: _} => {
+                        wi.shutdown().await?;
+                        write_open = false;
+                        if !read_open {
+                            break CloseReason::Eof;
+                        }
+                    }
+                    Message::CloseConnection{id: _, reason} => {
+                        break reason;
                     }
                     Message::Shutdown{message: _}=> {
-                        break;
+                        break CloseReason::Eof;
                     }
                     _ => {
                         Err(anyhow::anyhow!("Unexpected message"))?;
@@ -40,48 +181,515 @@ async fn handle_connection_inner(
                 }
 
             }
-            num_bytes = ri.read(&mut buf) => {
+            num_bytes = ri.read(&mut buf[..limit]), if limit > 0 => {
                 debug!("message from connection id={}, {:?}[bytes]", conn_id, num_bytes);
                 let num_bytes = num_bytes?;
                 if num_bytes == 0 {
-                    break;
+                    if !half_close {
+                        break CloseReason::Eof;
+                    }
+                    // keep writing until the peer shuts down its side as well
+                    read_open = false;
+                    s2c_tx.send(Message::ShutdownWrite { id: conn_id }).await?;
+                    if !write_open {
+                        break CloseReason::Eof;
+                    }
+                    continue;
                 }
-                s2c_tx.send(Message::Data { id: conn_id, data: buf[0..num_bytes].iter().cloned().collect()}).await?;
+                credit -= num_bytes;
+                s2c_tx.send(Message::Data { id: conn_id, data: buf[0..num_bytes].to_vec()}).await?;
             }
         }
+    };
+
+    drop((ri, wi));
+    if reason.is_reset() {
+        if let Some(page) = conn.error_page(reason).filter(|_| !written) {
+            conn.write_all(&page).await?;
+            conn.shutdown().await?;
+            return Ok(reason);
+        }
+        // send RST instead of FIN when dropped
+        conn.set_reset()?;
     }
-    Ok(())
+    Ok(reason)
 }
 
-pub async fn handle_connection(
+// Pumps data between `conn` and the tunnel. Messages for this connection
+// arrive on `rx`, as opened by ConnectionTable::open.
+pub async fn handle_connection<C: Conn>(
     conn_id: u32,
-    rx: tokio::sync::mpsc::Receiver<Message>,
-    s2c_tx: tokio::sync::mpsc::Sender<Message>,
-    conn: TcpStream,
+    rx: Receiver<Message>,
+    s2c_tx: Sender<Message>,
+    mut conn: C,
+    capabilities: Capabilities,
 ) -> anyhow::Result<()> {
     let tx = s2c_tx.clone();
-    let r = handle_connection_inner(conn_id, rx, s2c_tx, conn).await;
-    tx.send(Message::CloseConnection { id: conn_id }).await?;
-    r
+    let r = handle_connection_inner(conn_id, rx, s2c_tx, &mut conn, capabilities).await;
+    let reason = match &r {
+        Ok(reason) => *reason,
+        Err(e) => {
+            // failed halfway, the outside must not take it for a clean close
+            let _ = conn.set_reset();
+            e.downcast_ref::<std::io::Error>()
+                .map_or(CloseReason::IoError, CloseReason::from_io_error)
+        }
+    };
+    tx.send(Message::CloseConnection {
+        id: conn_id,
+        reason,
+    })
+    .await?;
+    r.map(|_| ())
+}
+
+// Connects to the backend for connection `id` and pumps its data. Only this
+// connection fails when the backend cannot be reached, the session stays up.
+pub async fn connect_backend<C, F>(
+    id: u32,
+    connect: F,
+    timeout: Duration,
+    rx: Receiver<Message>,
+    s2c_tx: Sender<Message>,
+    capabilities: Capabilities,
+) -> anyhow::Result<()>
+where
+    C: Conn,
+    F: Future<Output = std::io::Result<C>>,
+{
+    let reason = match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(conn)) => return handle_connection(id, rx, s2c_tx, conn, capabilities).await,
+        Ok(Err(e)) => {
+            warn!("failed to connect backend, id={}, {}", id, e);
+            CloseReason::from_io_error(&e)
+        }
+        Err(_) => {
+            warn!("timeout connecting backend, id={}", id);
+            CloseReason::Timeout
+        }
+    };
+    s2c_tx.send(Message::CloseConnection { id, reason }).await?;
+    Ok(())
+}
+
+pub fn get_identity_from_env() -> anyhow::Result<StaticSecret> {
+    // export IDENTITY_KEY=<private key printed by the base64 binary>
+    let k = general_purpose::STANDARD.decode(std::env::var("IDENTITY_KEY")?)?;
+    let k: [u8; 32] = k
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("identity key length must be 32"))?;
+    Ok(StaticSecret::from(k))
 }
 
-pub fn get_key_and_nonce_from_env(key: &mut [u8; 32], nonce: &mut [u8; 12]) {
-    // export ENCRYPT_KEY=rPc0ATOUqsySPNoWtwa10+fSzLSNOq1vRJqz9qK0Aag=
-    // export ENCRYPT_NONCE=uUXAxcAFLg+tLQsG
-    let k = general_purpose::STANDARD
-        .decode(std::env::var("ENCRYPT_KEY").unwrap())
-        .unwrap();
-    let n = general_purpose::STANDARD
-        .decode(std::env::var("ENCRYPT_NONCE").unwrap())
-        .unwrap();
+pub fn parse_public_key(s: &str) -> anyhow::Result<PublicKey> {
+    let k = general_purpose::STANDARD.decode(s.trim())?;
+    let k: [u8; 32] = k
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("public key length must be 32"))?;
+    Ok(PublicKey::from(k))
+}
+
+// One base64 public key per line, optionally followed by a comment naming the
+// client. Empty lines and lines starting with '#' are ignored.
+pub fn load_authorized_keys(path: &str) -> anyhow::Result<Vec<PublicKey>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| parse_public_key(line.split_whitespace().next().unwrap_or_default()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn data(id: u32, bytes: usize) -> Message {
+        Message::Data {
+            id,
+            data: vec![0u8; bytes],
+        }
+    }
+
+    #[test]
+    fn stray_frames() {
+        let mut table = ConnectionTable::default();
+        let _rx1 = table.open(1, Capabilities::supported());
+        let _rx2 = table.open(2, Capabilities::supported());
+        table.remove(1);
+
+        assert!(matches!(table.route(2, data(2, 1)), Route::Queued));
+        assert!(matches!(table.route(1, data(1, 1)), Route::Closed(_)));
+        assert!(matches!(table.route(3, data(3, 1)), Route::Unknown(_)));
+        assert!(matches!(table.route(0, data(0, 1)), Route::Unknown(_)));
+        assert_eq!(table.stray_frames(), 3);
+    }
+
+    #[test]
+    fn overrun() {
+        let mut table = ConnectionTable::default();
+        let _rx = table.open(1, Capabilities::supported());
+        let window = INITIAL_WINDOW as usize;
+        assert!(matches!(
+            table.route(1, data(1, window - 10)),
+            Route::Queued
+        ));
+        table.grant(1, 5);
+        assert!(matches!(table.route(1, data(1, 15)), Route::Queued));
+        assert!(matches!(table.route(1, data(1, 0)), Route::Overrun));
+        // dropped, later frames are stray
+        assert!(matches!(table.route(1, data(1, 1)), Route::Closed(_)));
+
+        // without flow control the queue is bounded instead
+        let mut table = ConnectionTable::default();
+        let _rx = table.open(1, Capabilities::NONE);
+        for _ in 0..QUEUE_WITHOUT_FLOW_CONTROL {
+            assert!(matches!(table.route(1, data(1, 8192)), Route::Queued));
+        }
+        assert!(matches!(table.route(1, data(1, 8192)), Route::Overrun));
+    }
+
+    async fn pair() -> anyhow::Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        Ok((client, server))
+    }
+
+    async fn received(s2c_rx: &mut tokio::sync::mpsc::Receiver<Message>) -> usize {
+        let mut total = 0;
+        while let Ok(Some(message)) =
+            tokio::time::timeout(std::time::Duration::from_millis(100), s2c_rx.recv()).await
+        {
+            if let Message::Data { data, .. } = message {
+                total += data.len();
+            }
+        }
+        total
+    }
+
+    #[tokio::test]
+    async fn backend_close() -> anyhow::Result<()> {
+        let mut table = ConnectionTable::default();
+        let capabilities = Capabilities::supported().without(Capabilities::HALF_CLOSE);
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        let (backend1, conn1) = pair().await?;
+        let (mut backend2, conn2) = pair().await?;
+        let rx1 = table.open(1, capabilities);
+        let rx2 = table.open(2, capabilities);
+        tokio::spawn(handle_connection(
+            1,
+            rx1,
+            s2c_tx.clone(),
+            conn1,
+            capabilities,
+        ));
+        tokio::spawn(handle_connection(2, rx2, s2c_tx, conn2, capabilities));
+
+        // the backend closing is reported for its connection alone
+        drop(backend1);
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::CloseConnection {
+                id: 1,
+                reason: CloseReason::Eof
+            })
+        ));
+        assert!(table.remove(1).is_some());
+
+        // late frames for it are stray, the other connection carries on
+        assert!(matches!(table.route(1, data(1, 1)), Route::Closed(_)));
+        let message = Message::Data {
+            id: 2,
+            data: b"ping".to_vec(),
+        };
+        assert!(matches!(table.route(2, message), Route::Queued));
+        let mut buf = [0u8; 4];
+        backend2.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_refused() -> anyhow::Result<()> {
+        let mut table = ConnectionTable::default();
+        let capabilities = Capabilities::supported();
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        let (mut backend1, conn1) = pair().await?;
+        let rx1 = table.open(1, capabilities);
+        tokio::spawn(handle_connection(
+            1,
+            rx1,
+            s2c_tx.clone(),
+            conn1,
+            capabilities,
+        ));
+
+        // nothing listens there anymore
+        let refused = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let rx2 = table.open(2, capabilities);
+        let timeout = Duration::from_secs(10);
+        connect_backend(
+            2,
+            TcpStream::connect(refused),
+            timeout,
+            rx2,
+            s2c_tx,
+            capabilities,
+        )
+        .await?;
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::CloseConnection {
+                id: 2,
+                reason: CloseReason::ConnectRefused
+            })
+        ));
+        assert!(table.remove(2).is_some());
+
+        let message = Message::Data {
+            id: 1,
+            data: b"ping".to_vec(),
+        };
+        assert!(matches!(table.route(1, message), Route::Queued));
+        let mut buf = [0u8; 4];
+        backend1.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    // in memory, to run with a paused clock
+    impl Conn for tokio::io::DuplexStream {
+        fn set_reset(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_timeout() -> anyhow::Result<()> {
+        let mut table = ConnectionTable::default();
+        let capabilities = Capabilities::supported();
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        let timeout = Duration::from_secs(10);
+        let started = tokio::time::Instant::now();
+
+        // a backend that never answers, early data waits for it
+        let blackhole = std::future::pending::<std::io::Result<tokio::io::DuplexStream>>();
+        let rx1 = table.open(1, capabilities);
+        tokio::spawn(connect_backend(
+            1,
+            blackhole,
+            timeout,
+            rx1,
+            s2c_tx.clone(),
+            capabilities,
+        ));
+        assert!(matches!(table.route(1, data(1, 4)), Route::Queued));
+
+        // meanwhile the other connection is served
+        let (mut backend2, conn2) = tokio::io::duplex(1024);
+        let rx2 = table.open(2, capabilities);
+        let connect = std::future::ready(Ok(conn2));
+        tokio::spawn(connect_backend(
+            2,
+            connect,
+            timeout,
+            rx2,
+            s2c_tx,
+            capabilities,
+        ));
+        let message = Message::Data {
+            id: 2,
+            data: b"ping".to_vec(),
+        };
+        assert!(matches!(table.route(2, message), Route::Queued));
+        let mut buf = [0u8; 4];
+        backend2.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        backend2.write_all(b"pong").await?;
+        loop {
+            match s2c_rx.recv().await {
+                Some(Message::Data { id: 2, data }) => {
+                    assert_eq!(data, b"pong");
+                    break;
+                }
+                Some(Message::WindowUpdate { id: 2, .. }) => {}
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        assert!(started.elapsed() < timeout);
+
+        // until the connect gives up on its own connection
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::CloseConnection {
+                id: 1,
+                reason: CloseReason::Timeout
+            })
+        ));
+        assert_eq!(started.elapsed(), timeout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn window() -> anyhow::Result<()> {
+        let (mut outside, conn) = pair().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(handle_connection(
+            1,
+            rx,
+            s2c_tx,
+            conn,
+            Capabilities::supported(),
+        ));
+
+        let payload = vec![0u8; INITIAL_WINDOW as usize + 10000];
+        tokio::spawn(async move { outside.write_all(&payload).await });
+
+        assert_eq!(received(&mut s2c_rx).await, INITIAL_WINDOW as usize);
+
+        tx.send(Message::WindowUpdate { id: 1, bytes: 4000 })
+            .await?;
+        assert_eq!(received(&mut s2c_rx).await, 4000);
+
+        tx.send(Message::WindowUpdate {
+            id: 1,
+            bytes: INITIAL_WINDOW,
+        })
+        .await?;
+        assert_eq!(received(&mut s2c_rx).await, 6000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn window_update_on_write() -> anyhow::Result<()> {
+        let (mut outside, conn) = pair().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(handle_connection(
+            1,
+            rx,
+            s2c_tx,
+            conn,
+            Capabilities::supported(),
+        ));
 
-    if k.len() < 32 {
-        panic!("encrypt_key length must be greater than 32");
+        tx.send(Message::Data {
+            id: 1,
+            data: b"hogehoge".to_vec(),
+        })
+        .await?;
+        let mut buf = [0u8; 8];
+        outside.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hogehoge");
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::WindowUpdate { id: 1, bytes: 8 })
+        ));
+
+        Ok(())
     }
-    if n.len() < 12 {
-        panic!("encrypt_key length must be greater than 32");
+
+    #[tokio::test]
+    async fn half_close() -> anyhow::Result<()> {
+        let (mut outside, conn) = pair().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(handle_connection(
+            1,
+            rx,
+            s2c_tx,
+            conn,
+            Capabilities::supported(),
+        ));
+
+        // the outside shuts down its write side and still gets a response
+        outside.write_all(b"ping").await?;
+        outside.shutdown().await?;
+        assert!(matches!(s2c_rx.recv().await, Some(Message::Data { .. })));
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::ShutdownWrite { id: 1 })
+        ));
+
+        tx.send(Message::Data {
+            id: 1,
+            data: b"pong".to_vec(),
+        })
+        .await?;
+        tx.send(Message::ShutdownWrite { id: 1 }).await?;
+        let mut buf = Vec::new();
+        outside.read_to_end(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::WindowUpdate { id: 1, bytes: 4 })
+        ));
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::CloseConnection {
+                id: 1,
+                reason: CloseReason::Eof
+            })
+        ));
+
+        Ok(())
     }
 
-    (0..32).into_iter().for_each(|i| key[i] = k[i]);
-    (0..12).into_iter().for_each(|i| nonce[i] = n[i]);
+    #[tokio::test]
+    async fn reset() -> anyhow::Result<()> {
+        let (mut outside, conn) = pair().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (s2c_tx, _s2c_rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(handle_connection(
+            1,
+            rx,
+            s2c_tx,
+            conn,
+            Capabilities::supported(),
+        ));
+
+        tx.send(Message::CloseConnection {
+            id: 1,
+            reason: CloseReason::ConnectRefused,
+        })
+        .await?;
+        let mut buf = Vec::new();
+        let e = outside.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_gone() -> anyhow::Result<()> {
+        let (mut outside, conn) = pair().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let (s2c_tx, mut s2c_rx) = tokio::sync::mpsc::channel(1024);
+        tokio::spawn(handle_connection(
+            1,
+            rx,
+            s2c_tx,
+            conn,
+            Capabilities::supported(),
+        ));
+
+        drop(tx);
+        let mut buf = Vec::new();
+        let e = outside.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(matches!(
+            s2c_rx.recv().await,
+            Some(Message::CloseConnection {
+                id: 1,
+                reason: CloseReason::IoError
+            })
+        ));
+
+        Ok(())
+    }
 }